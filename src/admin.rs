@@ -0,0 +1,206 @@
+use hyper::server::{Request, Response};
+use hyper::status::StatusCode;
+use hyper::header::{Authorization, Headers};
+
+use super::auth;
+use super::audit::Source;
+use super::server::ServerHandle;
+use super::oauth::parse_form;
+
+/// csrf token of the session of a cookie authenticated api request
+header! {(XVolfCsrf, "X-Volf-Csrf") => [String]}
+
+fn csrf_header(headers: &Headers) -> Option<&str> {
+    headers.get::<XVolfCsrf>().map(|&XVolfCsrf(ref t)| t.as_str())
+}
+
+/// Actions of `POST /api/pr/..`
+const PR_ACTIONS: [&'static str; 7] =
+    ["approve", "block", "unblock", "retry", "reset", "priority", "remove"];
+
+/// Authenticated actions on the queue
+impl ServerHandle {
+    /// Whether a request carries the admin token
+    pub fn is_admin(&self, headers: &Headers) -> bool {
+        let cfg = self.cfg();
+        match (cfg.admin_token.as_ref(), headers.get::<Authorization<String>>()) {
+            (Some(token), Some(&Authorization(ref given))) => {
+                auth::token_eq(&format!("token {}", &token[..]), given)
+            }
+            _ => false,
+        }
+    }
+
+    /// User allowed to manage the queue of `repo`
+    ///
+    /// Either the holder of the admin token (as "admin"), or a logged in reviewer
+    /// sending the `csrf` token of their session.
+    pub fn authorize(&self, repo: &str, headers: &Headers, csrf: Option<&str>)
+                     -> Option<String> {
+        if self.is_admin(headers) {
+            return Some("admin".into());
+        }
+        let session = match self.session(headers) {
+            Some(s) => s,
+            None => return None,
+        };
+        if !session.csrf_ok(csrf) {
+            warn!("Missing or wrong csrf token from {}", session.login);
+            return None;
+        }
+        match self.repository(repo) {
+            Some(ref r) if r.reviewers().contains(&session.login) => Some(session.login),
+            _ => None,
+        }
+    }
+
+    /// Apply an action to a tracked PR, returning whether the action was accepted
+    fn pr_action(&self, repo: &str, num: u64, action: &str, query: &str, user: &str)
+                 -> Option<bool> {
+        let mut prs = self.prs.lock().unwrap();
+        let idx = match prs.iter().position(|pr| pr.num == num && pr.repo == repo) {
+            Some(i) => i,
+            None => return None,
+        };
+        if !PR_ACTIONS.iter().any(|a| *a == action) {
+            return None;
+        }
+        info!("{}#{} - {} from {} via http", repo, num, action, user);
+        let cmd = if query.is_empty() {
+            action.to_string()
+        } else {
+            format!("{}?{}", action, query)
+        };
+        prs[idx].record_command(user, &cmd);
+        let accepted = match action {
+            "approve" => Some(prs[idx].approve(user)),
+            "block" => Some(prs[idx].block()),
+            "unblock" => {
+                prs[idx].unblock();
+                Some(true)
+            }
+            "retry" => Some(prs[idx].retry()),
+            "reset" => {
+                prs[idx].reset();
+                Some(true)
+            }
+            "priority" => {
+                let p = parse_form(query)
+                    .into_iter()
+                    .find(|&(ref k, _)| k == "p")
+                    .and_then(|(_, v)| v.parse().ok());
+                match p {
+                    Some(p) => {
+                        prs[idx].set_priority(p);
+                        Some(true)
+                    }
+                    None => Some(false),
+                }
+            }
+            "remove" => {
+                let mut pr = prs.remove(idx);
+                pr.untrack("removed via http");
+                self.record_audit(&mut pr, Source::Http);
+                return Some(true);
+            }
+            _ => None,
+        };
+        self.record_audit(&mut prs[idx], Source::Http);
+        accepted
+    }
+
+    /// POST /api/pr/<owner>/<repo>/<num>/<action>
+    ///
+    /// Actions are approve, block, unblock, retry, reset, remove and priority?p=N
+    pub fn handle_pr_action(&self, path: &str, query: &str, req: Request, mut res: Response) {
+        let parts = path.split('/').collect::<Vec<_>>();
+        if parts.len() != 4 {
+            *res.status_mut() = StatusCode::NotFound;
+            return;
+        }
+        let repo = format!("{}/{}", parts[0], parts[1]);
+        let num = match parts[2].parse::<u64>() {
+            Ok(n) => n,
+            Err(_) => {
+                *res.status_mut() = StatusCode::NotFound;
+                return;
+            }
+        };
+        let user = match self.authorize(&repo, &req.headers, csrf_header(&req.headers)) {
+            Some(u) => u,
+            None => {
+                warn!("Rejecting {} on {}#{}", parts[3], repo, num);
+                *res.status_mut() = StatusCode::Forbidden;
+                return;
+            }
+        };
+        match self.pr_action(&repo, num, parts[3], query, &user) {
+            Some(true) => {
+                self.queue_for(&repo);
+                res.send(b"ok").ok();
+            }
+            Some(false) => {
+                *res.status_mut() = StatusCode::Conflict;
+                res.send(b"not allowed in current state").ok();
+            }
+            None => *res.status_mut() = StatusCode::NotFound,
+        }
+    }
+
+    /// POST /api/repo/<owner>/<repo>/sync
+    pub fn handle_repo_action(&self, path: &str, req: Request, mut res: Response) {
+        let (repo, action) = match path.rfind('/') {
+            Some(i) => (&path[..i], &path[i + 1..]),
+            None => {
+                *res.status_mut() = StatusCode::NotFound;
+                return;
+            }
+        };
+        let cfg = match self.repository(repo) {
+            Some(ref r) if action == "sync" => r.clone(),
+            _ => {
+                *res.status_mut() = StatusCode::NotFound;
+                return;
+            }
+        };
+        let user = match self.authorize(repo, &req.headers, csrf_header(&req.headers)) {
+            Some(u) => u,
+            None => {
+                warn!("Rejecting {} on {}", action, repo);
+                *res.status_mut() = StatusCode::Forbidden;
+                return;
+            }
+        };
+        info!("{} - sync from {} via http", repo, user);
+        match self.synchronize(&cfg) {
+            Ok(_) => {
+                self.queue_for(&cfg.name);
+                res.send(b"ok").ok();
+            }
+            Err(err) => {
+                warn!("Failed to synchronize {}: {}", repo, err);
+                *res.status_mut() = StatusCode::InternalServerError;
+            }
+        }
+    }
+
+    /// POST /api/config/reload (admin token only)
+    pub fn handle_config_reload(&self, req: Request, mut res: Response) {
+        if !self.is_admin(&req.headers) {
+            warn!("Rejecting config reload");
+            *res.status_mut() = StatusCode::Forbidden;
+            return;
+        }
+        info!("config reload via http");
+        match self.reload() {
+            Ok(_) => {
+                res.send(b"ok").ok();
+            }
+            Err(err) => {
+                warn!("Config reload failed, keeping the running config: {}", err);
+                *res.status_mut() = StatusCode::UnprocessableEntity;
+                res.send(err.to_string().as_bytes()).ok();
+            }
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::mem;
+use hyper::server::Response;
+use hyper::status::StatusCode;
+use hyper::header::{ContentType, Headers};
+use serde_json;
+use time;
+
+use super::{Pull, Progress, VolfResult};
+use super::server::ServerHandle;
+
+/// Where a change to a PR came from
+#[derive(Serialize, Clone, Copy, Debug)]
+pub enum Source {
+    /// A PR comment
+    Comment,
+    /// The summary of a PR review
+    Review,
+    /// The admin api or the queue page
+    Http,
+    /// Github webhook events other than comments and reviews
+    Webhook,
+    /// A build result from CI
+    Ci,
+    /// The queue starting a build
+    Queue,
+    /// Corrections from periodic reconciliation
+    Reconcile,
+    /// State rebuilt by a full synchronization
+    Sync,
+    /// A config reload
+    Reload,
+}
+
+/// Something that happened to a PR
+#[derive(Serialize, Clone, Debug, PartialEq, Eq, PartialOrd)]
+pub enum Action {
+    /// A command was issued
+    Command(String),
+    /// The PR moved between states
+    Transition {
+        from: Progress,
+        to: Progress,
+        reason: String,
+    },
+    /// volf stopped tracking the PR
+    Untracked(String),
+}
+
+/// An action buffered on a Pull until the server records it
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd)]
+pub struct Event {
+    /// User issuing the command, if any
+    pub user: Option<String>,
+    /// Changeset the action applied to
+    pub sha: String,
+    /// What happened
+    pub action: Action,
+}
+
+/// A single entry of the audit trail
+#[derive(Serialize, Clone, Debug)]
+pub struct Record {
+    /// RFC 3339 timestamp in UTC
+    pub time: String,
+    /// The full owner/repo string
+    pub repo: String,
+    /// The pull request number
+    pub num: u64,
+    /// Where the action came from
+    pub source: Source,
+    /// User issuing the command, if any
+    pub user: Option<String>,
+    /// Changeset the action applied to
+    pub sha: String,
+    /// What happened
+    pub action: Action,
+}
+
+/// Number of records kept in memory (`audit_log` keeps everything)
+pub const MAX_RECORDS: usize = 10000;
+
+/// Audit trail of every command and state transition
+pub struct Audit {
+    /// The most recent records, oldest first
+    records: Vec<Record>,
+    /// Optional JSON Lines file every record is appended to
+    path: Option<String>,
+}
+
+impl Audit {
+    pub fn new(path: Option<String>) -> Audit {
+        Audit {
+            records: vec![],
+            path: path,
+        }
+    }
+
+    fn append(&self, record: &Record) -> VolfResult<()> {
+        if let Some(ref path) = self.path {
+            let mut f = OpenOptions::new().create(true).append(true).open(path)?;
+            write!(f, "{}\n", serde_json::to_string(record)?)?;
+        }
+        Ok(())
+    }
+
+    pub fn record(&mut self, record: Record) {
+        let _ = self.append(&record).map_err(|e| warn!("Failed to write audit log: {}", e));
+        self.records.push(record);
+        if self.records.len() > MAX_RECORDS {
+            let excess = self.records.len() - MAX_RECORDS;
+            self.records.drain(..excess);
+        }
+    }
+
+    /// All records of a single PR in order
+    pub fn history(&self, repo: &str, num: u64) -> Vec<&Record> {
+        self.records.iter().filter(|r| r.repo == repo && r.num == num).collect()
+    }
+
+    /// All records as JSON Lines
+    pub fn json_lines(&self) -> VolfResult<String> {
+        let mut out = String::new();
+        for r in &self.records {
+            out.push_str(&serde_json::to_string(r)?);
+            out.push('\n');
+        }
+        Ok(out)
+    }
+}
+
+/// Audit recording and routes
+impl ServerHandle {
+    /// Move the events buffered on a PR into the audit trail
+    pub fn record_audit(&self, pr: &mut Pull, source: Source) {
+        let events = pr.take_events();
+        if events.is_empty() {
+            return;
+        }
+        let now = time::now_utc().rfc3339().to_string();
+        let mut audit = self.audit.lock().unwrap();
+        for e in events {
+            audit.record(Record {
+                time: now.clone(),
+                repo: pr.repo.clone(),
+                num: pr.num,
+                source: source,
+                user: e.user,
+                sha: e.sha,
+                action: e.action,
+            });
+        }
+    }
+
+    /// Stop tracking the PRs matching `drop`, recording why in the audit trail
+    ///
+    /// Takes the locked PR list. Returns the number of PRs dropped.
+    pub fn untrack<F>(&self, prs: &mut Vec<Pull>, reason: &str, source: Source, drop: F)
+                      -> usize
+        where F: Fn(&Pull) -> bool
+    {
+        let (mut dropped, kept): (Vec<Pull>, Vec<Pull>) =
+            mem::replace(prs, vec![]).into_iter().partition(|pr| drop(pr));
+        *prs = kept;
+        for pr in &mut dropped {
+            pr.untrack(reason);
+            self.record_audit(pr, source);
+        }
+        dropped.len()
+    }
+
+    /// GET /api/pr/<owner>/<repo>/<num>/history
+    pub fn handle_history(&self, path: &str, mut res: Response) {
+        let parts = path.split('/').collect::<Vec<_>>();
+        let num = parts.get(2).and_then(|n| n.parse::<u64>().ok());
+        let (repo, num) = match (parts.len(), num) {
+            (4, Some(n)) if parts[3] == "history" => (format!("{}/{}", parts[0], parts[1]), n),
+            _ => {
+                *res.status_mut() = StatusCode::NotFound;
+                return;
+            }
+        };
+        let json = {
+            let audit = self.audit.lock().unwrap();
+            serde_json::to_string(&audit.history(&repo, num))
+        };
+        match json {
+            Ok(json) => {
+                res.headers_mut().set(ContentType::json());
+                res.send(json.as_bytes()).ok();
+            }
+            Err(err) => {
+                warn!("Failed to serialize history: {}", err);
+                *res.status_mut() = StatusCode::InternalServerError;
+            }
+        }
+    }
+
+    /// GET /api/audit - the whole trail as JSON Lines (admin token only)
+    pub fn handle_audit_export(&self, headers: &Headers, mut res: Response) {
+        if !self.is_admin(headers) {
+            *res.status_mut() = StatusCode::Forbidden;
+            return;
+        }
+        let lines = self.audit.lock().unwrap().json_lines();
+        match lines {
+            Ok(lines) => {
+                res.headers_mut().set(ContentType::plaintext());
+                res.send(lines.as_bytes()).ok();
+            }
+            Err(err) => {
+                warn!("Failed to serialize audit log: {}", err);
+                *res.status_mut() = StatusCode::InternalServerError;
+            }
+        }
+    }
+}
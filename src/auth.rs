@@ -0,0 +1,49 @@
+use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha1::Sha1;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+
+/// Hex encoded HMAC of a payload
+fn hmac_hex<D: Digest>(digest: D, secret: &str, payload: &str) -> String {
+    let mut hmac = Hmac::new(digest, secret.as_bytes());
+    hmac.input(payload.as_bytes());
+    hmac.result()
+        .code()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>()
+}
+
+/// Hex encoded HMAC-SHA1 of a payload in the `sha1=digest` form github uses
+pub fn sign(secret: &str, payload: &str) -> String {
+    format!("sha1={}", hmac_hex(Sha1::new(), secret, payload))
+}
+
+/// Hex encoded HMAC-SHA256 of a payload (the form gitea uses)
+pub fn sign_sha256(secret: &str, payload: &str) -> String {
+    hmac_hex(Sha256::new(), secret, payload)
+}
+
+/// Hex encoded SHA256 digest of a payload (to identify it)
+pub fn digest(payload: &str) -> String {
+    let mut sha = Sha256::new();
+    sha.input_str(payload);
+    sha.result_str()
+}
+
+/// Compare a secret token against a given one in constant time
+pub fn token_eq(expected: &str, given: &str) -> bool {
+    fixed_time_eq(expected.as_bytes(), given.as_bytes())
+}
+
+/// Verify a `sha1=digest` signature against a shared secret in constant time
+pub fn verify(secret: &str, payload: &str, signature: &str) -> bool {
+    fixed_time_eq(sign(secret, payload).as_bytes(), signature.as_bytes())
+}
+
+/// Verify a hex HMAC-SHA256 signature against a shared secret in constant time
+pub fn verify_sha256(secret: &str, payload: &str, signature: &str) -> bool {
+    fixed_time_eq(sign_sha256(secret, payload).as_bytes(), signature.as_bytes())
+}
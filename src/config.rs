@@ -1,78 +1,557 @@
 use serde_json;
+use serde_json::Value;
+use serde_yaml;
+use toml;
 
-use std::path::Path;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 use std::fs;
+use std::io;
 use std::vec::Vec;
 use std::io::prelude::{Read, Write};
 use std::process::Command;
 use std::env;
-use std::sync::Arc;
 use errors::{VolfError, VolfResult};
-use super::{Pull, parse_commands};
+use super::{Pull, Progress, parse_commands};
+use super::pullrequest::parse_tree_command;
+use super::forge::{Forge, PullInfo};
+use super::secret::Secret;
+use super::validate::{self, Problem};
 
-use hubcaps::Github;
+/// Latest tree command per base branch with the time it was given
+type TreeCommands = BTreeMap<String, (String, Option<u32>)>;
+
+/// Labels with special meaning to volf
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct Labels {
+    /// Label marking a PR for rollups
+    pub rollup: Option<String>,
+    /// Label blocking a PR from testing
+    pub block: Option<String>,
+}
+
+/// Service hosting a repository
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ForgeKind {
+    #[serde(rename = "github")]
+    Github,
+    #[serde(rename = "gitlab")]
+    Gitlab,
+    #[serde(rename = "gitea")]
+    Gitea,
+}
+
+impl Default for ForgeKind {
+    fn default() -> ForgeKind { ForgeKind::Github }
+}
+
+/// How a PR that passed its builds lands on its base branch
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum MergeStrategy {
+    /// The tested merge commit becomes the base
+    #[serde(rename = "merge")]
+    Merge,
+    /// The changes of the PR land as a single commit
+    #[serde(rename = "squash")]
+    Squash,
+    /// The commits of the PR are replayed onto the base
+    #[serde(rename = "rebase")]
+    Rebase,
+}
+
+impl Default for MergeStrategy {
+    fn default() -> MergeStrategy { MergeStrategy::Merge }
+}
+
+impl MergeStrategy {
+    /// Name of the strategy as configured (and as the forge apis call it)
+    pub fn name(&self) -> &'static str {
+        match *self {
+            MergeStrategy::Merge => "merge",
+            MergeStrategy::Squash => "squash",
+            MergeStrategy::Rebase => "rebase",
+        }
+    }
+}
+
+/// A base branch with its own queue
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Branch {
+    /// Name of the base branch
+    pub name: String,
+    /// Branch to test merges on (defaults to auto for the default branch, auto-<name> otherwise)
+    #[serde(default)]
+    pub auto: Option<String>,
+    /// Required status builds for this branch (defaults to the repository ones)
+    #[serde(default)]
+    pub required_builds: Option<Vec<String>>,
+}
+
+/// A resolved merge queue for a base branch of a repository
+#[derive(Clone, Debug)]
+pub struct Queue {
+    /// Repository owner + name
+    pub repo: String,
+    /// Base branch PRs are merged into
+    pub base: String,
+    /// Branch merges are tested on
+    pub auto: String,
+    /// Required status builds before merging
+    pub required_builds: Vec<String>,
+}
+
+/// Default name of the auto branch for a base branch
+fn auto_branch(base: &str, default_branch: &str) -> String {
+    if base == default_branch {
+        "auto".into()
+    } else {
+        format!("auto-{}", base.replace('/', "-"))
+    }
+}
+
+/// Whether a repository name is a pattern like `myorg/*`
+pub fn is_pattern(name: &str) -> bool { name.contains('*') }
+
+/// Whether a repository name matches a pattern where `*` stands for part of a path segment
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    match pattern.find('*') {
+        None => pattern == name,
+        Some(i) => {
+            if !name.starts_with(&pattern[..i]) {
+                return false;
+            }
+            let (rest, tail) = (&pattern[i + 1..], &name[i..]);
+            let segment = tail.find('/').unwrap_or(tail.len());
+            (0..segment + 1).any(|j| tail.is_char_boundary(j) && glob_match(rest, &tail[j..]))
+        }
+    }
+}
 
 /// Repository data
+///
+/// Settings left unset are inherited from matching pattern entries, then `defaults`.
+/// Values that are set win, even empty ones.
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Repository {
-    /// Repository owner + name
+    /// Repository owner + name, or a pattern like `myorg/*`
     pub name: String,
     /// Required status builds (with same name)
-    pub required_builds: Vec<String>,
+    #[serde(default)]
+    pub required_builds: Option<Vec<String>>,
     /// Optional status builds (with same name)
-    pub optional_builds: Vec<String>,
-    /// Github secret
-    pub github_secret: String,
+    #[serde(default)]
+    pub optional_builds: Option<Vec<String>>,
+    /// Webhook secret (github and gitea)
+    #[serde(default)]
+    pub github_secret: Option<Secret>,
+    /// Github users allowed to approve and manage the queue
+    #[serde(default)]
+    pub reviewers: Option<Vec<String>>,
+    /// Labels volf reacts to
+    #[serde(default)]
+    pub labels: Labels,
+    /// Base branches with independent queues (the default branch only if empty)
+    #[serde(default)]
+    pub branches: Option<Vec<Branch>>,
+    /// How tested PRs land (merge if unset)
+    #[serde(default)]
+    pub merge_strategy: Option<MergeStrategy>,
+    /// Service hosting the repository (github, gitlab or gitea)
+    #[serde(default)]
+    pub forge: ForgeKind,
+    /// Layer `.volf.toml` from the first base branch over these settings
+    #[serde(default)]
+    pub repo_config: Option<bool>,
+    /// Default branch on the forge, once volf has looked it up
+    #[serde(skip_serializing, skip_deserializing)]
+    pub default_branch: Option<String>,
 }
 
 impl Repository {
-    pub fn synchronize(&self, gh: Arc<Github>, repo: &str) -> VolfResult<Vec<Pull>> {
-        use hubcaps::issues::State;
-        use hubcaps::pulls::{PullRequests, PullListOptionsBuilder};
-        use hubcaps::comments::{Comments, CommentListOptions};
-        // TODO:  wipe state related to this repo!
-
-        // GET request to repos/{}/issues
-        let params = PullListOptionsBuilder::new().state(State::Open).build();
-        let repoz = repo.split('/').collect::<Vec<_>>();
-        let issues = PullRequests::new(&gh, repoz[0], repoz[1]);
-        let issue_list = issues.list(&params)?;
-
-        // state to replace old state with..
-        let mut result_list = vec![];
+    /// Required status builds
+    pub fn required_builds(&self) -> &[String] {
+        self.required_builds.as_ref().map_or(&[], |b| b.as_slice())
+    }
+
+    /// Optional status builds
+    pub fn optional_builds(&self) -> &[String] {
+        self.optional_builds.as_ref().map_or(&[], |b| b.as_slice())
+    }
+
+    /// Webhook secret (empty if unset)
+    pub fn github_secret(&self) -> &str { self.github_secret.as_ref().map_or("", |s| &s[..]) }
+
+    /// Users allowed to approve and manage the queue
+    pub fn reviewers(&self) -> &[String] {
+        self.reviewers.as_ref().map_or(&[], |r| r.as_slice())
+    }
+
+    /// Base branches with their own queues
+    pub fn branches(&self) -> &[Branch] { self.branches.as_ref().map_or(&[], |b| b.as_slice()) }
+
+    /// Whether `.volf.toml` is layered over these settings
+    pub fn repo_config(&self) -> bool { self.repo_config.unwrap_or(false) }
+
+    /// How tested PRs land on their base
+    pub fn merge_strategy(&self) -> MergeStrategy { self.merge_strategy.unwrap_or_default() }
+
+    /// Default branch on the forge (master until it is known)
+    pub fn default_branch(&self) -> &str {
+        self.default_branch.as_ref().map_or("master", |b| b.as_str())
+    }
+
+    /// Independent merge queues of this repository, one per base branch
+    pub fn queues(&self) -> Vec<Queue> {
+        let default_branch = self.default_branch();
+        if self.branches().is_empty() {
+            return vec![Queue {
+                            repo: self.name.clone(),
+                            base: default_branch.into(),
+                            auto: auto_branch(default_branch, default_branch),
+                            required_builds: self.required_builds().to_vec(),
+                        }];
+        }
+        self.branches()
+            .iter()
+            .map(|b| {
+                Queue {
+                    repo: self.name.clone(),
+                    base: b.name.clone(),
+                    auto: b.auto.clone().unwrap_or_else(|| auto_branch(&b.name, default_branch)),
+                    required_builds: b.required_builds
+                        .clone()
+                        .unwrap_or_else(|| self.required_builds().to_vec()),
+                }
+            })
+            .collect()
+    }
+
+    /// The merge queue for a base branch, if it has one
+    pub fn queue(&self, base: &str) -> Option<Queue> {
+        self.queues().into_iter().find(|q| q.base == base)
+    }
+
+    /// Base branch `.volf.toml` is read from (the first one)
+    pub fn config_branch(&self) -> String { self.queues().remove(0).base }
+
+    /// Fill settings left unset from a less specific layer (a pattern entry or defaults)
+    pub fn inherit(&mut self, base: &Repository) {
+        if self.required_builds.is_none() {
+            self.required_builds = base.required_builds.clone();
+        }
+        if self.optional_builds.is_none() {
+            self.optional_builds = base.optional_builds.clone();
+        }
+        if self.github_secret.is_none() {
+            self.github_secret = base.github_secret.clone();
+        }
+        if self.reviewers.is_none() {
+            self.reviewers = base.reviewers.clone();
+        }
+        if self.labels.rollup.is_none() {
+            self.labels.rollup = base.labels.rollup.clone();
+        }
+        if self.labels.block.is_none() {
+            self.labels.block = base.labels.block.clone();
+        }
+        if self.branches.is_none() {
+            self.branches = base.branches.clone();
+        }
+        if self.merge_strategy.is_none() {
+            self.merge_strategy = base.merge_strategy;
+        }
+        if self.repo_config.is_none() {
+            self.repo_config = base.repo_config;
+        }
+    }
+
+    /// Settle settings no layer set on their empty values
+    fn resolve(&mut self) {
+        self.required_builds = Some(self.required_builds.take().unwrap_or_default());
+        self.optional_builds = Some(self.optional_builds.take().unwrap_or_default());
+        self.github_secret = Some(self.github_secret.take().unwrap_or_default());
+        self.reviewers = Some(self.reviewers.take().unwrap_or_default());
+        self.branches = Some(self.branches.take().unwrap_or_default());
+        self.merge_strategy = Some(self.merge_strategy());
+        self.repo_config = Some(self.repo_config());
+    }
+
+    /// Rebuild the state of an open PR from its comments, reviews and statuses
+    ///
+    /// Commands are replayed in chronological order, and the PR is moved to `head`
+    /// at the time it was committed, so approvals of older changesets are dropped.
+    pub fn synchronize_pull(&self, forge: &Forge, pull: &PullInfo) -> VolfResult<Pull> {
+        self.rebuild_pull(forge, pull, &mut BTreeMap::new())
+    }
 
-        for issue in issue_list {
-            println!("Found PR: {:?}", issue);
-            //   - create Pull struct instance
-            let mut pr = Pull::new("clux/volf", issue.id, &issue.title);
+    /// Rebuild an open PR, collecting the latest tree command of a reviewer per base branch
+    fn rebuild_pull(&self, forge: &Forge, pull: &PullInfo, trees: &mut TreeCommands)
+                    -> VolfResult<Pull> {
+        let (num, head) = (pull.number, &pull.head_sha);
+        let mut pr = Pull::new(&self.name, num, &pull.title);
+        pr.set_base(&pull.base);
+        pr.set_head_label(&pull.head_label);
+        pr.set_author(&pull.author);
 
-            //   - parse command on issue body
-            let comments = Comments::new(&gh, repoz[0], repoz[1], issue.id);
-            let comment_list = comments.list(&CommentListOptions::default())?;
+        // commands can come from both comments and review summaries
+        let mut events = forge.comments(&self.name, num)?
+            .into_iter()
+            .map(|c| (c.created_at, c.user, c.body))
+            .collect::<Vec<_>>();
+        // timestamps are ISO 8601 in UTC so they sort lexically
+        events.sort();
 
-            for comment in comment_list {
-                println!(" - {}: {}", comment.user.login, comment.body);
-                parse_commands(&mut pr, comment.body, comment.user.login);
+        let head_date = forge.pushed_at(&self.name, num, head)?;
+        let mut at_head = false;
+        for (at, user, body) in events {
+            if !at_head && at >= head_date {
+                pr.set_head(head);
+                at_head = true;
+            }
+            debug!(" - {}: {}", user, body);
+            match parse_tree_command(&body) {
+                Some(closed) if self.reviewers().contains(&user) => {
+                    if trees.get(&pull.base).map_or(true, |&(ref last, _)| last <= &at) {
+                        trees.insert(pull.base.clone(), (at.clone(), closed));
+                    }
+                }
+                _ => {}
             }
-            // TODO: parse github reviews: https://developer.github.com/v3/pulls/reviews/
-            // need to add this to hubcaps - not supported atm it looks like.
+            parse_commands(&mut pr, body, user, self.reviewers());
+        }
+        if !at_head {
+            pr.set_head(head);
+        }
 
-            result_list.push(pr);
+        // our own status on head tells us if the last build of this head failed
+        let statuses = forge.statuses(&self.name, head)?;
+        if let Some(status) = statuses.iter().find(|s| s.context == "volf") {
+            let failed = status.state == "failure" || status.state == "error";
+            if pr.state == Progress::Pending && failed {
+                pr.failure();
+            }
+        }
+        Ok(pr)
+    }
 
+    /// Rebuild the state of every open PR in this repository
+    ///
+    /// Also returns the tree state per base branch from the latest `treeclosed` or
+    /// `treeopen` of a reviewer on an open PR (None when the tree was reopened).
+    pub fn synchronize(&self, forge: &Forge)
+                       -> VolfResult<(Vec<Pull>, BTreeMap<String, Option<u32>>)> {
+        let mut result_list = vec![];
+        let mut trees = BTreeMap::new();
+        for pull in forge.open_pulls(&self.name)? {
+            info!("Synchronizing {}#{}", self.name, pull.number);
+            result_list.push(self.rebuild_pull(forge, &pull, &mut trees)?);
         }
-        Ok(result_list)
+        let trees = trees.into_iter().map(|(base, (_, closed))| (base, closed)).collect();
+        Ok((result_list, trees))
     }
 }
 
-/// Github specific tokens and data
+/// CI backend allowed to report build results
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CiBackend {
+    /// Name of the backend (sent in the X-Volf-Ci header)
+    pub name: String,
+    /// Shared secret used to sign build results
+    pub secret: Secret,
+}
+
+/// Settings shared by repositories (`defaults`, and `defaults` of a github app)
 #[derive(Serialize, Deserialize, Default, Clone)]
+pub struct RepositoryDefaults {
+    /// Required status builds (with same name)
+    #[serde(default)]
+    pub required_builds: Option<Vec<String>>,
+    /// Optional status builds (with same name)
+    #[serde(default)]
+    pub optional_builds: Option<Vec<String>>,
+    /// Webhook secret
+    #[serde(default)]
+    pub github_secret: Option<Secret>,
+    /// Github users allowed to approve and manage the queue
+    #[serde(default)]
+    pub reviewers: Option<Vec<String>>,
+    /// Labels volf reacts to
+    #[serde(default)]
+    pub labels: Labels,
+    /// Base branches with independent queues (the default branch only if empty)
+    #[serde(default)]
+    pub branches: Option<Vec<Branch>>,
+    /// How tested PRs land
+    #[serde(default)]
+    pub merge_strategy: Option<MergeStrategy>,
+    /// Layer `.volf.toml` from the first base branch over these settings
+    #[serde(default)]
+    pub repo_config: Option<bool>,
+}
+
+impl RepositoryDefaults {
+    /// These settings for a repository on github
+    pub fn repository(&self, name: &str) -> Repository {
+        Repository {
+            name: name.into(),
+            required_builds: self.required_builds.clone(),
+            optional_builds: self.optional_builds.clone(),
+            github_secret: self.github_secret.clone(),
+            reviewers: self.reviewers.clone(),
+            labels: self.labels.clone(),
+            branches: self.branches.clone(),
+            merge_strategy: self.merge_strategy,
+            forge: ForgeKind::Github,
+            repo_config: self.repo_config,
+            default_branch: None,
+        }
+    }
+}
+
+/// Name of the per-repository config file read from the base branch
+pub const REPO_FILE: &'static str = ".volf.toml";
+
+/// Settings a repository can override in its own `.volf.toml`
+///
+/// Unset fields keep the central settings, and unknown fields make the file invalid.
+#[derive(Deserialize, Default, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RepoFile {
+    /// Required status builds (with same name)
+    pub required_builds: Option<Vec<String>>,
+    /// Optional status builds (with same name)
+    pub optional_builds: Option<Vec<String>>,
+    /// Users allowed to approve and manage the queue
+    pub reviewers: Option<Vec<String>>,
+    /// Labels volf reacts to
+    pub labels: Option<Labels>,
+    /// How tested PRs land
+    pub merge_strategy: Option<MergeStrategy>,
+}
+
+impl RepoFile {
+    /// Parse a `.volf.toml`
+    pub fn parse(data: &str) -> VolfResult<RepoFile> {
+        let file: RepoFile = toml::from_str(data)?;
+        Ok(file)
+    }
+
+    /// Layer these settings over the central settings of a repository
+    pub fn apply(&self, repo: &mut Repository) {
+        if self.required_builds.is_some() {
+            repo.required_builds = self.required_builds.clone();
+        }
+        if self.optional_builds.is_some() {
+            repo.optional_builds = self.optional_builds.clone();
+        }
+        if self.reviewers.is_some() {
+            repo.reviewers = self.reviewers.clone();
+        }
+        if let Some(ref labels) = self.labels {
+            repo.labels = labels.clone();
+        }
+        if self.merge_strategy.is_some() {
+            repo.merge_strategy = self.merge_strategy;
+        }
+    }
+}
+
+/// Github app volf runs as instead of a personal access token
+///
+/// Repositories the app is installed on are tracked without being listed in
+/// `repositories`. Installed repositories no entry matches use the app `defaults`,
+/// layered over the top level `defaults`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct GithubApp {
+    /// Numeric id of the app
+    pub app_id: u64,
+    /// Path to the PEM encoded private key of the app
+    pub private_key: String,
+    /// Webhook secret of the app (signs installation events)
+    #[serde(default)]
+    pub webhook_secret: Secret,
+    /// Settings of installed repositories not listed in `repositories`
+    #[serde(default)]
+    pub defaults: RepositoryDefaults,
+}
+
+/// Proxy and certificate settings for outgoing connections
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct Connection {
+    /// PEM file of extra certificate authorities to trust (e.g. an internal CA)
+    #[serde(default)]
+    pub ca_bundle: Option<String>,
+    /// HTTP proxy as host:port
+    #[serde(default)]
+    pub proxy: Option<String>,
+}
+
+/// Github specific tokens and data
+#[derive(Serialize, Deserialize, Clone)]
 pub struct GithubData {
-    /// Personal access token for volf app host
-    pub access_token: String,
+    /// Api root (https://HOST/api/v3 for github enterprise)
+    #[serde(default = "default_api_url")]
+    pub api_url: String,
+    /// Web root for logins and links (https://HOST for github enterprise)
+    #[serde(default = "default_web_url")]
+    pub web_url: String,
+    /// Personal access token for volf app host (GITHUB_TOKEN if empty)
+    #[serde(default)]
+    pub access_token: Secret,
+    /// Authenticate as a github app rather than with GITHUB_TOKEN
+    #[serde(default)]
+    pub app: Option<GithubApp>,
     /// Client id for volf app
     pub app_client_id: String,
     /// Client secret for volf app
-    pub app_client_secret: String,
+    pub app_client_secret: Secret,
+    /// Login of the volf machine account (the only user allowed to push to auto)
+    #[serde(default = "default_login")]
+    pub login: String,
+}
+
+fn default_login() -> String { "volf".into() }
+fn default_api_url() -> String { "https://api.github.com".into() }
+fn default_web_url() -> String { "https://github.com".into() }
+
+impl Default for GithubData {
+    fn default() -> Self {
+        GithubData {
+            api_url: default_api_url(),
+            web_url: default_web_url(),
+            access_token: Secret::default(),
+            app: None,
+            app_client_id: String::new(),
+            app_client_secret: Secret::default(),
+            login: default_login(),
+        }
+    }
+}
+
+/// Self-hosted gitlab instance
+#[derive(Serialize, Deserialize, Clone)]
+pub struct GitlabData {
+    /// Root url of the instance (e.g. https://gitlab.example.com)
+    pub url: String,
+    /// Personal access token of the volf user
+    pub access_token: Secret,
+    /// Secret token configured on webhooks (sent as X-Gitlab-Token)
+    pub webhook_token: Secret,
+    /// Username of the volf user
+    #[serde(default = "default_login")]
+    pub login: String,
+}
+
+/// Self-hosted gitea (or forgejo) instance
+#[derive(Serialize, Deserialize, Clone)]
+pub struct GiteaData {
+    /// Root url of the instance (e.g. https://gitea.example.com)
+    pub url: String,
+    /// Access token of the volf user
+    pub access_token: Secret,
+    /// Username of the volf user
+    #[serde(default = "default_login")]
+    pub login: String,
 }
 
 /// Representation of `volf.json`
@@ -85,9 +564,45 @@ pub struct Config {
     /// Github tokens and client
     pub github: GithubData,
 
-    // TOOD: CI usernames, tokens and urls
-    /// Repositories to watch
+    /// Gitlab instance for repositories with `"forge": "gitlab"`
+    #[serde(default)]
+    pub gitlab: Option<GitlabData>,
+
+    /// Gitea instance for repositories with `"forge": "gitea"`
+    #[serde(default)]
+    pub gitea: Option<GiteaData>,
+
+    /// Proxy and certificate authorities for outgoing connections
+    #[serde(default)]
+    pub connection: Connection,
+
+    /// Settings of repositories wherever their entries leave them empty
+    #[serde(default)]
+    pub defaults: RepositoryDefaults,
+
+    /// Repositories to watch (names or patterns like `myorg/*`)
     pub repositories: Vec<Repository>,
+
+    /// Token for the admin http api (sent as `Authorization: token ...`)
+    #[serde(default)]
+    pub admin_token: Option<Secret>,
+
+    /// Seconds between reconciliations of tracked PRs against github (disabled if unset)
+    #[serde(default)]
+    pub reconcile_interval: Option<u64>,
+
+    /// Path of an append-only log of raw webhook events (for `volf replay`)
+    #[serde(default)]
+    pub event_log: Option<String>,
+
+    /// Path of an append-only JSON lines log of every command and state transition
+    #[serde(default)]
+    pub audit_log: Option<String>,
+
+    // TODO: CI usernames and urls
+    /// CI backends allowed to POST build results
+    #[serde(default)]
+    pub ci: Vec<CiBackend>,
 }
 
 impl Default for Config {
@@ -95,46 +610,273 @@ impl Default for Config {
         Config {
             port: 54857,
             github: GithubData::default(),
+            gitlab: None,
+            gitea: None,
+            connection: Connection::default(),
+            defaults: RepositoryDefaults::default(),
             repositories: vec![],
+            admin_token: None,
+            reconcile_interval: None,
+            event_log: None,
+            audit_log: None,
+            ci: vec![],
         }
     }
 }
 
 impl Config {
-    /// Read and deserialize a Config from volf.json
-    pub fn read() -> VolfResult<Config> {
-        let cfg_path = Path::new("volf.json");
+    /// Find a configured repository entry by owner/name
+    pub fn repository(&self, name: &str) -> Option<&Repository> {
+        self.repositories.iter().find(|r| r.name == name)
+    }
+
+    /// Layer the pattern entries matching a repository, then `defaults`, under its settings
+    ///
+    /// Settings no layer sets are resolved to their empty values.
+    pub fn layer(&self, mut repo: Repository) -> Repository {
+        for pattern in self.repositories.iter().filter(|p| is_pattern(&p.name)) {
+            if glob_match(&pattern.name, &repo.name) {
+                repo.inherit(pattern);
+            }
+        }
+        repo.inherit(&self.defaults.repository(&repo.name));
+        repo.resolve();
+        repo
+    }
+
+    /// Effective settings of every configured repository
+    ///
+    /// Patterns are expanded against the repositories `visible` on their forge. Entries
+    /// naming a repository come first, and override the patterns matching it.
+    pub fn expand(&self, visible: &BTreeMap<ForgeKind, Vec<String>>) -> Vec<Repository> {
+        let mut repos = self.repositories
+            .iter()
+            .filter(|r| !is_pattern(&r.name))
+            .map(|r| self.layer(r.clone()))
+            .collect::<Vec<_>>();
+        for pattern in self.repositories.iter().filter(|r| is_pattern(&r.name)) {
+            let names = visible.get(&pattern.forge).map(|v| v.as_slice()).unwrap_or(&[]);
+            for name in names.iter().filter(|n| glob_match(&pattern.name, n)) {
+                if !repos.iter().any(|r| &r.name == name) {
+                    let mut repo = pattern.clone();
+                    repo.name = name.clone();
+                    repos.push(self.layer(repo));
+                }
+            }
+        }
+        repos
+    }
+
+    /// Login volf acts as on a forge
+    pub fn forge_login(&self, forge: ForgeKind) -> &str {
+        let login = match forge {
+            ForgeKind::Github => None,
+            ForgeKind::Gitlab => self.gitlab.as_ref().map(|gl| &gl.login),
+            ForgeKind::Gitea => self.gitea.as_ref().map(|gt| &gt.login),
+        };
+        login.unwrap_or(&self.github.login)
+    }
+
+    /// Web page of a pull request (merge request on gitlab) of a repository on a forge
+    pub fn pull_url(&self, forge: ForgeKind, repo: &str, num: u64) -> String {
+        let url = |u: &str| u.trim_right_matches('/').to_string();
+        match forge {
+            ForgeKind::Github => format!("{}/{}/pull/{}", url(&self.github.web_url), repo, num),
+            ForgeKind::Gitlab => {
+                let web = self.gitlab.as_ref().map_or(String::new(), |gl| url(&gl.url));
+                format!("{}/{}/-/merge_requests/{}", web, repo, num)
+            }
+            ForgeKind::Gitea => {
+                let web = self.gitea.as_ref().map_or(String::new(), |gt| url(&gt.url));
+                format!("{}/{}/pulls/{}", web, repo, num)
+            }
+        }
+    }
+
+    /// Every secret of the config, named after where it is set
+    pub fn secrets(&self) -> Vec<(String, &Secret)> {
+        let gh = &self.github;
+        let mut secrets = vec![("github.access_token".into(), &gh.access_token),
+                               ("github.app_client_secret".into(), &gh.app_client_secret)];
+        if let Some(ref secret) = self.defaults.github_secret {
+            secrets.push(("defaults.github_secret".into(), secret));
+        }
+        if let Some(ref app) = gh.app {
+            secrets.push(("github.app.webhook_secret".into(), &app.webhook_secret));
+            if let Some(ref secret) = app.defaults.github_secret {
+                secrets.push(("github.app.defaults.github_secret".into(), secret));
+            }
+        }
+        if let Some(ref gl) = self.gitlab {
+            secrets.push(("gitlab.access_token".into(), &gl.access_token));
+            secrets.push(("gitlab.webhook_token".into(), &gl.webhook_token));
+        }
+        if let Some(ref gt) = self.gitea {
+            secrets.push(("gitea.access_token".into(), &gt.access_token));
+        }
+        if let Some(ref token) = self.admin_token {
+            secrets.push(("admin_token".into(), token));
+        }
+        for repo in &self.repositories {
+            if let Some(ref secret) = repo.github_secret {
+                secrets.push((format!("github_secret of repository {}", repo.name), secret));
+            }
+        }
+        for ci in &self.ci {
+            secrets.push((format!("secret of ci backend {}", ci.name), &ci.secret));
+        }
+        secrets
+    }
+
+    /// Find a configured CI backend by name
+    pub fn ci_backend(&self, name: &str) -> Option<&CiBackend> {
+        self.ci.iter().find(|ci| ci.name == name)
+    }
+
+    /// Path of the config: `--config`, else `VOLF_CONFIG`, else volf.json in the current directory
+    pub fn path(flag: Option<&str>) -> PathBuf {
+        flag.map(PathBuf::from)
+            .or_else(|| env::var_os("VOLF_CONFIG").map(PathBuf::from))
+            .unwrap_or_else(|| PathBuf::from("volf.json"))
+    }
+
+    /// Raw contents of the config
+    fn source(cfg_path: &Path) -> VolfResult<String> {
         if !cfg_path.exists() {
-            return Err(VolfError::MissingConfig);
+            return Err(VolfError::MissingConfig(cfg_path.display().to_string()));
         }
         let mut f = fs::File::open(&cfg_path)?;
         let mut cfg_str = String::new();
         f.read_to_string(&mut cfg_str)?;
-        let res: Config = serde_json::from_str(&cfg_str)?;
+        Ok(cfg_str)
+    }
+
+    /// Deserialize a Config in the format of its path
+    pub fn parse(cfg_path: &Path, cfg_str: &str) -> VolfResult<Config> {
+        let res: Config = match Format::of(cfg_path) {
+            Format::Json => serde_json::from_str(cfg_str)?,
+            Format::Toml => toml::from_str(cfg_str)?,
+            Format::Yaml => serde_yaml::from_str(cfg_str)?,
+        };
         Ok(res)
     }
 
-    pub fn generate() -> VolfResult<()> {
-        let cfg_path = Path::new("volf.json");
+    /// Serialize a Config in the format of its path
+    fn encode(&self, cfg_path: &Path) -> VolfResult<String> {
+        match Format::of(cfg_path) {
+            Format::Json => Ok(serde_json::to_string_pretty(self)?),
+            Format::Toml => {
+                // toml has no null, and toml::Value puts plain values before tables
+                let json = without_nulls(serde_json::to_value(self)?);
+                let value: toml::Value = serde_json::from_value(json)?;
+                toml::to_string(&value).map_err(|e| VolfError::Config(e.to_string()))
+            }
+            Format::Yaml => Ok(serde_yaml::to_string(self)?),
+        }
+    }
+
+    /// Read and deserialize a Config
+    ///
+    /// Fails naming the first secret whose references can not be resolved.
+    pub fn read(cfg_path: &Path) -> VolfResult<Config> {
+        let cfg = Config::parse(cfg_path, &Config::source(cfg_path)?)?;
+        for (name, secret) in cfg.secrets() {
+            if let Some(e) = secret.error() {
+                return Err(VolfError::Config(format!("{}: {}", name, e)));
+            }
+        }
+        Ok(cfg)
+    }
+
+    /// Read the config and check it for values that parse but can not work
+    ///
+    /// Secrets are checked without being resolved, so this works away from where
+    /// volf runs.
+    pub fn validate(cfg_path: &Path) -> VolfResult<Vec<Problem>> {
+        let cfg_str = Config::source(cfg_path)?;
+        let cfg = Config::parse(cfg_path, &cfg_str)?;
+        Ok(validate::validate(&cfg, &cfg_str))
+    }
+
+    /// Write an empty config in the format of its path
+    pub fn generate(cfg_path: &Path) -> VolfResult<()> {
         if cfg_path.exists() {
-            return Err(VolfError::ConfigExists);
+            return Err(VolfError::ConfigExists(cfg_path.display().to_string()));
         }
         let cfg = Config::default();
-        let encoded = serde_json::to_string_pretty(&cfg)?;
+        let encoded = cfg.encode(cfg_path)?;
 
         let mut f = fs::File::create(&cfg_path)?;
-        write!(f, "{}\n", encoded)?;
+        write!(f, "{}\n", encoded.trim_right())?;
         info!("Wrote config {}: \n{}", cfg_path.display(), encoded);
         Ok(())
     }
 
-    pub fn edit() -> VolfResult<()> {
+    /// Open the config with $EDITOR until it is valid
+    ///
+    /// When editing is given up on an invalid config, the previous contents are restored.
+    pub fn edit(cfg_path: &Path) -> VolfResult<()> {
         let editor = env::var("EDITOR")
-            .map_err(|e| {
-                error!("Could not find $EDITOR - {}", e);
-            })
-            .unwrap();
-        Command::new(editor).arg("volf.json").status()?;
-        Ok(())
+            .map_err(|_| VolfError::Config("$EDITOR is not set to open the config with".into()))?;
+        // editors are commonly set with arguments, like `code --wait`
+        let mut words = editor.split_whitespace();
+        let program = words.next()
+            .ok_or_else(|| VolfError::Config("$EDITOR is empty".into()))?;
+        let args = words.collect::<Vec<_>>();
+        let original = Config::source(cfg_path)?;
+        loop {
+            Command::new(program).args(&args).arg(cfg_path).status()?;
+            let problems = match Config::validate(cfg_path) {
+                Ok(problems) => problems.iter().map(|p| p.to_string()).collect(),
+                Err(e) => vec![e.to_string()],
+            };
+            if problems.is_empty() {
+                return Ok(());
+            }
+            for p in &problems {
+                println!("{}: {}", cfg_path.display(), p);
+            }
+            print!("Edit again? Otherwise the previous config is restored [Y/n] ");
+            io::stdout().flush()?;
+            let mut answer = String::new();
+            let n = io::stdin().read_line(&mut answer)?;
+            if n == 0 || answer.trim().to_lowercase().starts_with('n') {
+                fs::File::create(cfg_path)?.write_all(original.as_bytes())?;
+                return Err(VolfError::Config(format!("{} was left unchanged", cfg_path.display())));
+            }
+        }
+    }
+}
+
+/// File formats of the config, chosen by extension
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl Format {
+    /// Format of a config path (json unless it ends in .toml, .yaml or .yml)
+    pub fn of(cfg_path: &Path) -> Format {
+        match cfg_path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => Format::Toml,
+            Some("yaml") | Some("yml") => Format::Yaml,
+            _ => Format::Json,
+        }
+    }
+}
+
+/// Drop null fields (unset options) from a json value
+fn without_nulls(value: Value) -> Value {
+    match value {
+        Value::Object(map) => {
+            Value::Object(map.into_iter()
+                .filter(|&(_, ref v)| !v.is_null())
+                .map(|(k, v)| (k, without_nulls(v)))
+                .collect())
+        }
+        Value::Array(values) => Value::Array(values.into_iter().map(without_nulls).collect()),
+        value => value,
     }
 }
@@ -1,8 +1,12 @@
 use std::fmt;
 use std::io;
 use serde_json;
+use serde_yaml;
+use toml;
 use hyper::Error as HttpError;
 use hubcaps::Error as HubError;
+use openssl::error::ErrorStack;
+use native_tls::Error as TlsError;
 
 /// The one and only error type for the volf library
 #[derive(Debug)]
@@ -11,17 +15,33 @@ pub enum VolfError {
     Io(io::Error),
     /// Errors propagated from sedre
     Parse(serde_json::error::Error),
+    /// Errors propagated from toml when reading a .toml config
+    Toml(toml::de::Error),
+    /// Errors propagated from serde_yaml when reading or writing a .yaml config
+    Yaml(serde_yaml::Error),
     /// Errors propagated from hyper
     Http(HttpError),
     /// Github API errors from `hubcaps` client
     Client(HubError),
+    /// Errors propagated from openssl (github app keys and certificates)
+    Ssl(ErrorStack),
+    /// Errors propagated from native-tls when setting up connections
+    Tls(TlsError),
 
-    /// Config (volf.json) not found in current working directory
-    MissingConfig,
+    /// Config (volf.json) not found at the given path
+    MissingConfig(String),
     /// Config exists when expected not to
-    ConfigExists,
+    ConfigExists(String),
     /// Misconfigured github webhooks - sends events we don't need
     SpammyGithub(String),
+    /// Request signature did not match the shared secret of the sender
+    InvalidSignature(String),
+    /// OAuth login flow failed
+    OAuth(String),
+    /// Request to the forge was rejected
+    Forge(String),
+    /// Config (volf.json) has an invalid value
+    Config(String),
 }
 
 // Format implementation used when printing an error
@@ -31,10 +51,18 @@ impl fmt::Display for VolfError {
             VolfError::Io(ref err) => err.fmt(f),
             VolfError::Parse(ref err) => err.fmt(f),
             VolfError::Http(ref err) => err.fmt(f),
-            VolfError::MissingConfig => write!(f, "Local config volf.json not found"),
-            VolfError::ConfigExists => write!(f, "Local config volf.json exists"),
+            VolfError::Toml(ref err) => err.fmt(f),
+            VolfError::Yaml(ref err) => err.fmt(f),
+            VolfError::MissingConfig(ref s) => write!(f, "Local config {} not found", s),
+            VolfError::ConfigExists(ref s) => write!(f, "Local config {} exists", s),
             VolfError::SpammyGithub(ref s) => write!(f, "{} events should not be sent to volf", s),
+            VolfError::InvalidSignature(ref s) => write!(f, "Invalid signature from {}", s),
+            VolfError::OAuth(ref s) => write!(f, "OAuth login failed: {}", s),
+            VolfError::Forge(ref s) => write!(f, "Forge request failed: {}", s),
             VolfError::Client(ref err) => err.fmt(f),
+            VolfError::Ssl(ref err) => err.fmt(f),
+            VolfError::Tls(ref err) => err.fmt(f),
+            VolfError::Config(ref s) => write!(f, "Invalid config: {}", s),
         }
     }
 }
@@ -47,10 +75,26 @@ impl From<serde_json::error::Error> for VolfError {
     fn from(err: serde_json::error::Error) -> VolfError { VolfError::Parse(err) }
 }
 
+impl From<toml::de::Error> for VolfError {
+    fn from(err: toml::de::Error) -> VolfError { VolfError::Toml(err) }
+}
+
+impl From<serde_yaml::Error> for VolfError {
+    fn from(err: serde_yaml::Error) -> VolfError { VolfError::Yaml(err) }
+}
+
 impl From<HubError> for VolfError {
     fn from(err: HubError) -> VolfError { VolfError::Client(err) }
 }
 
+impl From<ErrorStack> for VolfError {
+    fn from(err: ErrorStack) -> VolfError { VolfError::Ssl(err) }
+}
+
+impl From<TlsError> for VolfError {
+    fn from(err: TlsError) -> VolfError { VolfError::Tls(err) }
+}
+
 impl From<HttpError> for VolfError {
     fn from(error: HttpError) -> VolfError { VolfError::Http(error) }
 }
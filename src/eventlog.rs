@@ -0,0 +1,151 @@
+use std::collections::{BTreeSet, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use serde_json;
+
+use super::VolfResult;
+use super::config::ForgeKind;
+use super::server::ServerHandle;
+
+/// How many delivery ids to remember for deduplication
+const REMEMBERED_DELIVERIES: usize = 10000;
+
+/// A raw webhook event as received from a forge
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LoggedEvent {
+    /// Forge that sent the event
+    #[serde(default)]
+    pub forge: ForgeKind,
+    /// Value of the X-Github-Delivery header (or the equivalent of the forge)
+    pub delivery: String,
+    /// Value of the X-Github-Event header (or the equivalent of the forge)
+    pub event: String,
+    /// Raw payload
+    pub payload: String,
+}
+
+/// Recently seen delivery ids (bounded)
+#[derive(Default)]
+pub struct Deliveries {
+    seen: BTreeSet<String>,
+    order: VecDeque<String>,
+}
+
+impl Deliveries {
+    /// Remember the most recent deliveries in an event log
+    ///
+    /// This way redeliveries of events handled before a restart are still ignored.
+    pub fn from_log(path: Option<&String>) -> Deliveries {
+        let mut deliveries = Deliveries::default();
+        let path = match path {
+            Some(p) if Path::new(p).exists() => p,
+            _ => return deliveries,
+        };
+        match read(path) {
+            Ok(events) => {
+                let skip = events.len().saturating_sub(REMEMBERED_DELIVERIES);
+                for e in events.iter().skip(skip) {
+                    deliveries.insert(&e.delivery);
+                }
+            }
+            Err(e) => warn!("Failed to read deliveries from {}: {}", path, e),
+        }
+        deliveries
+    }
+
+    /// Remember a delivery id, returning false if it has been seen before
+    pub fn insert(&mut self, id: &str) -> bool {
+        if self.seen.contains(id) {
+            return false;
+        }
+        self.seen.insert(id.into());
+        self.order.push_back(id.into());
+        if self.order.len() > REMEMBERED_DELIVERIES {
+            if let Some(old) = self.order.pop_front() {
+                self.seen.remove(&old);
+            }
+        }
+        true
+    }
+}
+
+/// Append an event as a single json line to an append-only log
+pub fn append(path: &str, event: &LoggedEvent) -> VolfResult<()> {
+    let mut f = OpenOptions::new().create(true).append(true).open(path)?;
+    write!(f, "{}\n", serde_json::to_string(event)?)?;
+    Ok(())
+}
+
+/// Read every event in a log written by `append`
+pub fn read<P: AsRef<Path>>(path: P) -> VolfResult<Vec<LoggedEvent>> {
+    let f = BufReader::new(File::open(path)?);
+    let mut events = vec![];
+    for line in f.lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            events.push(serde_json::from_str(&line)?);
+        }
+    }
+    Ok(events)
+}
+
+/// Deduplication and recording of webhook deliveries
+impl ServerHandle {
+    /// Record a delivery, returning false if it was already handled
+    pub fn record_delivery(&self, forge: ForgeKind, id: &str, event: &str, payload: &str)
+                           -> bool {
+        let mut deliveries = self.deliveries.lock().unwrap();
+        if !deliveries.insert(id) {
+            return false;
+        }
+        if let Some(ref path) = self.cfg().event_log {
+            let logged = LoggedEvent {
+                forge: forge,
+                delivery: id.into(),
+                event: event.into(),
+                payload: payload.into(),
+            };
+            let _ = append(path, &logged)
+                .map_err(|e| warn!("Failed to log delivery {} to {}: {}", id, path, e));
+        }
+        true
+    }
+
+    /// Run every event in a log through the event handlers in order
+    ///
+    /// Meant for a server from `ServerHandle::for_replay`. Every repository named in
+    /// the log counts as visible, so repositories matched by patterns replay too.
+    pub fn replay<P: AsRef<Path>>(&self, path: P) -> VolfResult<usize> {
+        let events = read(path)?;
+        {
+            let mut visible = self.visible.lock().unwrap();
+            for e in &events {
+                let payload: serde_json::Value = serde_json::from_str(&e.payload)?;
+                let name = match e.forge {
+                    ForgeKind::Gitlab => {
+                        payload.get("project").and_then(|p| p.get("path_with_namespace"))
+                    }
+                    _ => payload.get("repository").and_then(|r| r.get("full_name")),
+                };
+                if let Some(name) = name.and_then(|n| n.as_str()) {
+                    let names = visible.entry(e.forge).or_insert_with(Vec::new);
+                    if !names.iter().any(|n| n == name) {
+                        names.push(name.into());
+                    }
+                }
+            }
+        }
+        self.refresh_repositories();
+        for e in &events {
+            debug!("replaying {} {}", e.event, e.delivery);
+            let handled = match e.forge {
+                ForgeKind::Gitlab => self.handle_gitlab_event(&e.event, &e.payload),
+                _ => self.handle_event(&e.event, &e.payload),
+            };
+            let _ = handled
+                .map_err(|err| warn!("Failed to replay {} {}: {}", e.event, e.delivery, err));
+        }
+        Ok(events.len())
+    }
+}
@@ -0,0 +1,389 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use base64;
+
+use super::{VolfError, VolfResult};
+use super::config::MergeStrategy;
+
+/// Prefix of temporary branches used to create merge commits
+///
+/// Gitlab and gitea have no api to merge a changeset into a branch, so volf pushes
+/// the head to a temporary branch and merges a PR from it instead.
+pub const MERGE_PREFIX: &'static str = "volf-merge/";
+
+/// An open pull request as listed by a forge
+#[derive(Clone, Debug)]
+pub struct PullInfo {
+    /// The pull request number
+    pub number: u64,
+    /// Title of PR
+    pub title: String,
+    /// Changeset id of the PR head
+    pub head_sha: String,
+    /// Owner and branch of the PR head joined by a colon
+    pub head_label: String,
+    /// Branch the PR targets
+    pub base: String,
+    /// Login of the PR author
+    pub author: String,
+}
+
+/// A comment or review summary on a pull request
+#[derive(Clone, Debug)]
+pub struct Comment {
+    /// Login of the commenter
+    pub user: String,
+    /// Comment text (may contain commands)
+    pub body: String,
+    /// ISO 8601 timestamp in UTC
+    pub created_at: String,
+}
+
+/// A commit status on a changeset
+#[derive(Deserialize, Clone, Debug)]
+pub struct Status {
+    /// Status name (e.g. a build name, or volf)
+    pub context: String,
+    /// pending / success / failure / error
+    pub state: String,
+}
+
+/// A file fetched through a forge api
+#[derive(Deserialize, Debug)]
+pub struct FileContent {
+    /// Base64 encoded contents (possibly broken into lines)
+    pub content: String,
+}
+
+impl FileContent {
+    /// Decoded contents of a text file
+    pub fn decode(&self) -> VolfResult<String> {
+        let data = self.content.chars().filter(|c| !c.is_whitespace()).collect::<String>();
+        let bytes = base64::decode(&data)
+            .map_err(|e| VolfError::Forge(format!("invalid file content: {}", e)))?;
+        String::from_utf8(bytes).map_err(|e| VolfError::Forge(format!("file is not utf-8: {}", e)))
+    }
+}
+
+/// Every operation volf needs from the service hosting its repositories
+pub trait Forge: Send + Sync {
+    /// Repositories the token can access (owner/name)
+    fn repositories(&self) -> VolfResult<Vec<String>>;
+
+    /// Branch a repository is created with, and new PRs target by default
+    fn default_branch(&self, repo: &str) -> VolfResult<String>;
+
+    /// Open pull requests of a repository
+    fn open_pulls(&self, repo: &str) -> VolfResult<Vec<PullInfo>>;
+
+    /// A single open pull request
+    fn pull(&self, repo: &str, num: u64) -> VolfResult<PullInfo> {
+        self.open_pulls(repo)?
+            .into_iter()
+            .find(|p| p.number == num)
+            .ok_or_else(|| VolfError::Forge(format!("no open pull request {}#{}", repo, num)))
+    }
+
+    /// Comments and review summaries on a pull request (in any order)
+    fn comments(&self, repo: &str, num: u64) -> VolfResult<Vec<Comment>>;
+
+    /// Statuses on a changeset, most recent first
+    fn statuses(&self, repo: &str, sha: &str) -> VolfResult<Vec<Status>>;
+
+    /// When `sha` was pushed as the head of a PR
+    ///
+    /// Comments before this time were made on an earlier head. Forges that do not
+    /// record every push fall back to the committer timestamp of the changeset.
+    fn pushed_at(&self, repo: &str, num: u64, sha: &str) -> VolfResult<String>;
+
+    /// Whether a PR merges cleanly into its base (None while not computed yet)
+    fn mergeable(&self, repo: &str, num: u64) -> VolfResult<Option<bool>>;
+
+    /// Changeset a branch points to
+    fn branch_head(&self, repo: &str, branch: &str) -> VolfResult<String>;
+
+    /// Contents of a file on a branch or at a changeset (None if it does not exist)
+    fn file(&self, repo: &str, git_ref: &str, path: &str) -> VolfResult<Option<String>>;
+
+    /// Point a branch at a changeset, creating the branch if necessary
+    ///
+    /// Without `force` only fast-forwards are allowed.
+    fn set_branch(&self, repo: &str, branch: &str, sha: &str, force: bool) -> VolfResult<()>;
+
+    /// Merge a changeset into a branch and return the merge commit (None on conflicts)
+    fn merge(&self, repo: &str, branch: &str, sha: &str, message: &str)
+             -> VolfResult<Option<String>>;
+
+    /// Set a commit status on a changeset
+    fn set_status(&self, repo: &str, sha: &str, context: &str, state: &str, description: &str)
+                  -> VolfResult<()>;
+
+    /// Post a comment on a pull request
+    fn comment(&self, repo: &str, num: u64, body: &str) -> VolfResult<()>;
+
+    /// Land a tested PR on its base branch
+    ///
+    /// Forges that can move branches fast-forward the base to the tested `merge`.
+    /// Other strategies need support from the forge.
+    fn land(&self, repo: &str, num: u64, base: &str, _head: &str, merge: &str,
+            strategy: MergeStrategy)
+            -> VolfResult<()> {
+        match strategy {
+            MergeStrategy::Merge => self.set_branch(repo, base, merge, false),
+            _ => {
+                Err(VolfError::Forge(format!("can not land {}#{} with {}",
+                                             repo,
+                                             num,
+                                             strategy.name())))
+            }
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// State of a single repository in the fake forge
+#[derive(Default)]
+struct FakeRepo {
+    pulls: Vec<PullInfo>,
+    comments: BTreeMap<u64, Vec<Comment>>,
+    statuses: BTreeMap<String, Vec<Status>>,
+    branches: BTreeMap<String, String>,
+    commit_dates: BTreeMap<String, String>,
+    /// Times changesets were pushed to PRs
+    pushes: BTreeMap<String, String>,
+    /// Parents of merge commits created by `merge`
+    parents: BTreeMap<String, Vec<String>>,
+    /// Head changesets that conflict with every branch
+    conflicts: Vec<String>,
+    /// File contents keyed by changeset and path
+    files: BTreeMap<(String, String), String>,
+    /// Default branch if not master
+    default_branch: Option<String>,
+}
+
+#[derive(Default)]
+struct FakeState {
+    repos: BTreeMap<String, FakeRepo>,
+    /// Logical clock for timestamps and merge commit ids
+    clock: u64,
+}
+
+impl FakeState {
+    fn tick(&mut self) -> String {
+        self.clock += 1;
+        format!("2017-01-01T{:02}:{:02}:{:02}Z",
+                self.clock / 3600,
+                (self.clock / 60) % 60,
+                self.clock % 60)
+    }
+    fn repo(&mut self, repo: &str) -> &mut FakeRepo {
+        self.repos.entry(repo.into()).or_insert_with(FakeRepo::default)
+    }
+}
+
+/// In-memory forge for testing the merge queue offline
+///
+/// Merges and branches are tracked by changeset id only, so fast-forward checks
+/// are not enforced. Moving a branch to a merge commit closes the PRs merged by it.
+#[derive(Default)]
+pub struct FakeForge {
+    state: Mutex<FakeState>,
+}
+
+impl FakeForge {
+    /// Open a pull request, committing its head now
+    pub fn add_pull(&self, repo: &str, pull: PullInfo) {
+        let mut state = self.state.lock().unwrap();
+        let now = state.tick();
+        let r = state.repo(repo);
+        r.commit_dates.insert(pull.head_sha.clone(), now);
+        r.pulls.retain(|p| p.number != pull.number);
+        r.pulls.push(pull);
+    }
+
+    /// Push a new head to an open pull request, committing it now
+    pub fn push_pull(&self, repo: &str, num: u64, sha: &str) {
+        let now = self.state.lock().unwrap().tick();
+        self.push_pull_dated(repo, num, sha, &now);
+    }
+
+    /// Push a new head to an open pull request that was committed at `date`
+    pub fn push_pull_dated(&self, repo: &str, num: u64, sha: &str, date: &str) {
+        let mut state = self.state.lock().unwrap();
+        let now = state.tick();
+        let r = state.repo(repo);
+        r.commit_dates.insert(sha.into(), date.into());
+        r.pushes.insert(sha.into(), now);
+        if let Some(p) = r.pulls.iter_mut().find(|p| p.number == num) {
+            p.head_sha = sha.into();
+        }
+    }
+
+    /// Close a pull request without merging it
+    pub fn close_pull(&self, repo: &str, num: u64) {
+        self.state.lock().unwrap().repo(repo).pulls.retain(|p| p.number != num);
+    }
+
+    /// Comment on a pull request as `user`
+    pub fn add_comment(&self, repo: &str, num: u64, user: &str, body: &str) {
+        let mut state = self.state.lock().unwrap();
+        let now = state.tick();
+        state.repo(repo).comments.entry(num).or_insert_with(Vec::new).push(Comment {
+            user: user.into(),
+            body: body.into(),
+            created_at: now,
+        });
+    }
+
+    /// Make every merge of a changeset fail with a conflict
+    pub fn add_conflict(&self, repo: &str, sha: &str) {
+        self.state.lock().unwrap().repo(repo).conflicts.push(sha.into());
+    }
+
+    /// Commit a file to a branch, moving the branch to `sha`
+    pub fn add_file(&self, repo: &str, branch: &str, sha: &str, path: &str, contents: &str) {
+        let mut state = self.state.lock().unwrap();
+        let now = state.tick();
+        let r = state.repo(repo);
+        r.commit_dates.insert(sha.into(), now);
+        r.branches.insert(branch.into(), sha.into());
+        r.files.insert((sha.into(), path.into()), contents.into());
+    }
+
+    /// Make another branch than master the default branch
+    pub fn set_default_branch(&self, repo: &str, branch: &str) {
+        self.state.lock().unwrap().repo(repo).default_branch = Some(branch.into());
+    }
+
+    /// Changeset a branch points to, if it exists
+    pub fn branch(&self, repo: &str, branch: &str) -> Option<String> {
+        self.state.lock().unwrap().repo(repo).branches.get(branch).cloned()
+    }
+
+    /// Most recent state of a commit status
+    pub fn status(&self, repo: &str, sha: &str, context: &str) -> Option<String> {
+        let mut state = self.state.lock().unwrap();
+        state.repo(repo)
+            .statuses
+            .get(sha)
+            .and_then(|s| s.iter().find(|s| s.context == context))
+            .map(|s| s.state.clone())
+    }
+
+    /// Whether a pull request is still open
+    pub fn is_open(&self, repo: &str, num: u64) -> bool {
+        self.state.lock().unwrap().repo(repo).pulls.iter().any(|p| p.number == num)
+    }
+}
+
+impl Forge for FakeForge {
+    fn repositories(&self) -> VolfResult<Vec<String>> {
+        Ok(self.state.lock().unwrap().repos.keys().cloned().collect())
+    }
+
+    fn default_branch(&self, repo: &str) -> VolfResult<String> {
+        let mut state = self.state.lock().unwrap();
+        Ok(state.repo(repo).default_branch.clone().unwrap_or_else(|| "master".into()))
+    }
+
+    fn open_pulls(&self, repo: &str) -> VolfResult<Vec<PullInfo>> {
+        Ok(self.state.lock().unwrap().repo(repo).pulls.clone())
+    }
+
+    fn comments(&self, repo: &str, num: u64) -> VolfResult<Vec<Comment>> {
+        let mut state = self.state.lock().unwrap();
+        Ok(state.repo(repo).comments.get(&num).cloned().unwrap_or_default())
+    }
+
+    fn statuses(&self, repo: &str, sha: &str) -> VolfResult<Vec<Status>> {
+        let mut state = self.state.lock().unwrap();
+        Ok(state.repo(repo).statuses.get(sha).cloned().unwrap_or_default())
+    }
+
+    fn pushed_at(&self, repo: &str, _: u64, sha: &str) -> VolfResult<String> {
+        let mut state = self.state.lock().unwrap();
+        let r = state.repo(repo);
+        r.pushes
+            .get(sha)
+            .or_else(|| r.commit_dates.get(sha))
+            .cloned()
+            .ok_or_else(|| VolfError::Forge(format!("unknown commit {}", sha)))
+    }
+
+    fn mergeable(&self, repo: &str, num: u64) -> VolfResult<Option<bool>> {
+        let mut state = self.state.lock().unwrap();
+        let r = state.repo(repo);
+        Ok(r.pulls.iter().find(|p| p.number == num).map(|p| !r.conflicts.contains(&p.head_sha)))
+    }
+
+    fn branch_head(&self, repo: &str, branch: &str) -> VolfResult<String> {
+        self.branch(repo, branch).ok_or_else(|| VolfError::Forge(format!("no branch {}", branch)))
+    }
+
+    fn file(&self, repo: &str, git_ref: &str, path: &str) -> VolfResult<Option<String>> {
+        let mut state = self.state.lock().unwrap();
+        let r = state.repo(repo);
+        let sha = r.branches.get(git_ref).cloned().unwrap_or_else(|| git_ref.into());
+        Ok(r.files.get(&(sha, path.into())).cloned())
+    }
+
+    fn set_branch(&self, repo: &str, branch: &str, sha: &str, _: bool) -> VolfResult<()> {
+        let mut state = self.state.lock().unwrap();
+        let r = state.repo(repo);
+        r.branches.insert(branch.into(), sha.into());
+        let merged = r.parents.get(sha).cloned().unwrap_or_default();
+        r.pulls.retain(|p| !(p.base == branch && merged.contains(&p.head_sha)));
+        Ok(())
+    }
+
+    /// Squashes and rebases land as a changeset named after the strategy and head
+    fn land(&self, repo: &str, num: u64, base: &str, head: &str, merge: &str,
+            strategy: MergeStrategy)
+            -> VolfResult<()> {
+        if strategy == MergeStrategy::Merge {
+            return self.set_branch(repo, base, merge, false);
+        }
+        let mut state = self.state.lock().unwrap();
+        let r = state.repo(repo);
+        r.branches.insert(base.into(), format!("{}-{}", strategy.name(), head));
+        r.pulls.retain(|p| p.number != num);
+        Ok(())
+    }
+
+    fn merge(&self, repo: &str, branch: &str, sha: &str, _: &str) -> VolfResult<Option<String>> {
+        let mut state = self.state.lock().unwrap();
+        let now = state.tick();
+        let merge = format!("merge{}", state.clock);
+        let r = state.repo(repo);
+        if r.conflicts.iter().any(|c| c == sha) {
+            return Ok(None);
+        }
+        let parent = match r.branches.get(branch) {
+            Some(p) => p.clone(),
+            None => return Err(VolfError::Forge(format!("no branch {}", branch))),
+        };
+        r.parents.insert(merge.clone(), vec![parent, sha.into()]);
+        r.commit_dates.insert(merge.clone(), now);
+        r.branches.insert(branch.into(), merge.clone());
+        Ok(Some(merge))
+    }
+
+    fn set_status(&self, repo: &str, sha: &str, context: &str, state: &str, _: &str)
+                  -> VolfResult<()> {
+        let mut fake = self.state.lock().unwrap();
+        let statuses = fake.repo(repo).statuses.entry(sha.into()).or_insert_with(Vec::new);
+        statuses.insert(0,
+                        Status {
+                            context: context.into(),
+                            state: state.into(),
+                        });
+        Ok(())
+    }
+
+    fn comment(&self, repo: &str, num: u64, body: &str) -> VolfResult<()> {
+        self.add_comment(repo, num, "volf", body);
+        Ok(())
+    }
+}
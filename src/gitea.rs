@@ -0,0 +1,429 @@
+use std::io::Read;
+
+use hyper::Client;
+use hyper::method::Method;
+use hyper::status::StatusCode;
+use hyper::header::{Authorization, ContentType, UserAgent};
+use serde::{Deserialize, Serialize};
+use serde_json;
+
+use super::{VolfError, VolfResult};
+use super::forge::{Forge, PullInfo, Comment, Status, FileContent, MERGE_PREFIX};
+use super::config::MergeStrategy;
+
+// -----------------------------------------------------------------------------
+// Response types
+
+#[derive(Deserialize, Debug)]
+struct User {
+    login: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct RepositoryName {
+    full_name: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct RepositoryInfo {
+    default_branch: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct PullRef {
+    #[serde(rename = "ref")]
+    git_ref: String,
+    label: String,
+    sha: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct PullRequest {
+    number: u64,
+    title: String,
+    user: User,
+    head: PullRef,
+    base: PullRef,
+    #[serde(default)]
+    mergeable: bool,
+    #[serde(default)]
+    merge_commit_sha: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct IssueComment {
+    user: User,
+    body: String,
+    created_at: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Review {
+    user: User,
+    #[serde(default)]
+    body: String,
+    #[serde(default)]
+    submitted_at: Option<String>,
+}
+
+/// An entry on the timeline of a PR
+#[derive(Deserialize, Debug)]
+struct TimelineComment {
+    #[serde(rename = "type")]
+    kind: String,
+    created_at: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct CommitStatus {
+    context: String,
+    status: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct CommitAuthor {
+    date: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct CommitData {
+    committer: CommitAuthor,
+}
+
+#[derive(Deserialize, Debug)]
+struct Commit {
+    commit: CommitData,
+}
+
+#[derive(Deserialize, Debug)]
+struct BranchCommit {
+    id: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Branch {
+    commit: BranchCommit,
+}
+
+// request bodies
+
+#[derive(Serialize)]
+struct BranchCreate<'a> {
+    new_branch_name: &'a str,
+    old_ref_name: &'a str,
+}
+
+#[derive(Serialize)]
+struct PullCreate<'a> {
+    head: &'a str,
+    base: &'a str,
+    title: &'a str,
+}
+
+#[derive(Serialize)]
+struct PullMerge<'a> {
+    #[serde(rename = "Do")]
+    merge_style: &'a str,
+    #[serde(rename = "MergeMessageField")]
+    message: &'a str,
+    /// Only merge if the head is still at this changeset
+    head_commit_id: &'a str,
+    delete_branch_after_merge: bool,
+}
+
+#[derive(Serialize)]
+struct StatusCreate<'a> {
+    state: &'a str,
+    context: &'a str,
+    description: &'a str,
+}
+
+#[derive(Serialize)]
+struct CommentCreate<'a> {
+    body: &'a str,
+}
+
+// -----------------------------------------------------------------------------
+
+/// Forge implementation for a gitea (or forgejo) instance (api v1)
+pub struct GiteaForge {
+    /// Api root
+    host: String,
+    /// Token sent with every request
+    token: String,
+    client: Client,
+}
+
+impl GiteaForge {
+    pub fn new(url: &str, token: &str, client: Client) -> GiteaForge {
+        GiteaForge {
+            host: format!("{}/api/v1", url.trim_right_matches('/')),
+            token: token.into(),
+            client: client,
+        }
+    }
+
+    fn request(&self, method: Method, uri: &str, body: Option<&str>)
+               -> VolfResult<(StatusCode, String)> {
+        let url = format!("{}/{}", self.host, uri);
+        let mut req = self.client
+            .request(method, &url)
+            .header(Authorization(format!("token {}", self.token)))
+            .header(UserAgent(format!("volf/{}", env!("CARGO_PKG_VERSION"))))
+            .header(ContentType::json());
+        if let Some(body) = body {
+            req = req.body(body);
+        }
+        let mut res = req.send()?;
+        let mut data = String::new();
+        res.read_to_string(&mut data)?;
+        Ok((res.status, data))
+    }
+
+    fn get<D: Deserialize>(&self, uri: &str) -> VolfResult<D> {
+        let (status, data) = self.request(Method::Get, uri, None)?;
+        if !status.is_success() {
+            return Err(VolfError::Forge(format!("{} {}: {}", uri, status, data)));
+        }
+        Ok(serde_json::from_str(&data)?)
+    }
+
+    fn send<S: Serialize>(&self, method: Method, uri: &str, body: &S)
+                          -> VolfResult<(StatusCode, String)> {
+        let body = serde_json::to_string(body)?;
+        self.request(method, uri, Some(&body))
+    }
+
+    fn write<S: Serialize>(&self, method: Method, uri: &str, body: &S) -> VolfResult<String> {
+        let (status, data) = self.send(method, uri, body)?;
+        if !status.is_success() {
+            return Err(VolfError::Forge(format!("{} {}: {}", uri, status, data)));
+        }
+        Ok(data)
+    }
+
+    /// Every page of a listing, 50 entries at a time
+    fn get_all<D: Deserialize>(&self, uri: &str) -> VolfResult<Vec<D>> {
+        let sep = if uri.contains('?') { '&' } else { '?' };
+        let mut all = vec![];
+        for page in 1.. {
+            let entries: Vec<D> = self.get(&format!("{}{}limit=50&page={}", uri, sep, page))?;
+            let n = entries.len();
+            all.extend(entries);
+            if n < 50 {
+                break;
+            }
+        }
+        Ok(all)
+    }
+
+    fn pull(&self, repo: &str, num: u64) -> VolfResult<PullRequest> {
+        self.get(&format!("repos/{}/pulls/{}", repo, num))
+    }
+
+    fn delete_branch(&self, repo: &str, branch: &str) -> VolfResult<()> {
+        let uri = format!("repos/{}/branches/{}", repo, branch);
+        let (status, data) = self.request(Method::Delete, &uri, None)?;
+        match status {
+            s if s.is_success() => Ok(()),
+            StatusCode::NotFound => Ok(()),
+            s => Err(VolfError::Forge(format!("{} {}: {}", uri, s, data))),
+        }
+    }
+
+    fn create_branch(&self, repo: &str, branch: &str, sha: &str) -> VolfResult<()> {
+        let create = BranchCreate {
+            new_branch_name: branch,
+            old_ref_name: sha,
+        };
+        self.write(Method::Post, &format!("repos/{}/branches", repo), &create)?;
+        Ok(())
+    }
+
+    /// Merge a PR and return the merge commit (None if gitea refuses the merge)
+    fn merge_pull(&self, repo: &str, num: u64, head: &str, message: &str, delete: bool,
+                  style: &str)
+                  -> VolfResult<Option<String>> {
+        let merge = PullMerge {
+            merge_style: style,
+            message: message,
+            head_commit_id: head,
+            delete_branch_after_merge: delete,
+        };
+        let uri = format!("repos/{}/pulls/{}/merge", repo, num);
+        let (status, data) = self.send(Method::Post, &uri, &merge)?;
+        match status {
+            s if s.is_success() => Ok(self.pull(repo, num)?.merge_commit_sha),
+            // 405 - not mergeable, 409 - head moved or conflicts
+            StatusCode::MethodNotAllowed | StatusCode::Conflict => Ok(None),
+            s => Err(VolfError::Forge(format!("{} {}: {}", uri, s, data))),
+        }
+    }
+}
+
+impl Forge for GiteaForge {
+    fn repositories(&self) -> VolfResult<Vec<String>> {
+        let repos: Vec<RepositoryName> = self.get_all("user/repos")?;
+        Ok(repos.into_iter().map(|r| r.full_name).collect())
+    }
+
+    fn default_branch(&self, repo: &str) -> VolfResult<String> {
+        let info: RepositoryInfo = self.get(&format!("repos/{}", repo))?;
+        Ok(info.default_branch)
+    }
+
+    fn open_pulls(&self, repo: &str) -> VolfResult<Vec<PullInfo>> {
+        let pulls: Vec<PullRequest> = self.get_all(&format!("repos/{}/pulls?state=open", repo))?;
+        Ok(pulls.into_iter()
+            .filter(|p| !p.head.git_ref.starts_with(MERGE_PREFIX))
+            .map(|p| {
+                PullInfo {
+                    number: p.number,
+                    title: p.title,
+                    head_sha: p.head.sha,
+                    head_label: p.head.label,
+                    base: p.base.git_ref,
+                    author: p.user.login,
+                }
+            })
+            .collect())
+    }
+
+    fn comments(&self, repo: &str, num: u64) -> VolfResult<Vec<Comment>> {
+        let comments: Vec<IssueComment> =
+            self.get_all(&format!("repos/{}/issues/{}/comments", repo, num))?;
+        let reviews: Vec<Review> = self.get_all(&format!("repos/{}/pulls/{}/reviews", repo, num))?;
+        let mut res = comments.into_iter()
+            .map(|c| {
+                Comment {
+                    user: c.user.login,
+                    body: c.body,
+                    created_at: c.created_at,
+                }
+            })
+            .collect::<Vec<_>>();
+        for review in reviews {
+            if let Some(at) = review.submitted_at {
+                res.push(Comment {
+                    user: review.user.login,
+                    body: review.body,
+                    created_at: at,
+                });
+            }
+        }
+        Ok(res)
+    }
+
+    fn statuses(&self, repo: &str, sha: &str) -> VolfResult<Vec<Status>> {
+        let statuses: Vec<CommitStatus> = self.get(&format!("repos/{}/commits/{}/statuses",
+                                                            repo,
+                                                            sha))?;
+        Ok(statuses.into_iter()
+            .map(|s| {
+                Status {
+                    context: s.context,
+                    state: s.status,
+                }
+            })
+            .collect())
+    }
+
+    /// Gitea adds a pull_push entry to the timeline for every push after the first
+    fn pushed_at(&self, repo: &str, num: u64, sha: &str) -> VolfResult<String> {
+        let commit: Commit = self.get(&format!("repos/{}/git/commits/{}", repo, sha))?;
+        let committed = commit.commit.committer.date;
+        let timeline: Vec<TimelineComment> =
+            self.get_all(&format!("repos/{}/issues/{}/timeline", repo, num))?;
+        let pushed = timeline.into_iter()
+            .filter(|c| c.kind == "pull_push")
+            .map(|c| c.created_at)
+            .max();
+        Ok(match pushed {
+            Some(pushed) if pushed > committed => pushed,
+            _ => committed,
+        })
+    }
+
+    fn mergeable(&self, repo: &str, num: u64) -> VolfResult<Option<bool>> {
+        Ok(Some(self.pull(repo, num)?.mergeable))
+    }
+
+    fn branch_head(&self, repo: &str, branch: &str) -> VolfResult<String> {
+        let branch: Branch = self.get(&format!("repos/{}/branches/{}", repo, branch))?;
+        Ok(branch.commit.id)
+    }
+
+    fn file(&self, repo: &str, git_ref: &str, path: &str) -> VolfResult<Option<String>> {
+        let uri = format!("repos/{}/contents/{}?ref={}", repo, path, git_ref);
+        let (status, data) = self.request(Method::Get, &uri, None)?;
+        match status {
+            StatusCode::NotFound => Ok(None),
+            s if s.is_success() => {
+                let file: FileContent = serde_json::from_str(&data)?;
+                file.decode().map(Some)
+            }
+            s => Err(VolfError::Forge(format!("{} {}: {}", uri, s, data))),
+        }
+    }
+
+    /// Gitea can not move branches, so forced moves recreate the branch
+    fn set_branch(&self, repo: &str, branch: &str, sha: &str, force: bool) -> VolfResult<()> {
+        if !force {
+            return Err(VolfError::Forge(format!("gitea can not fast-forward {}", branch)));
+        }
+        self.delete_branch(repo, branch)?;
+        self.create_branch(repo, branch, sha)
+    }
+
+    fn merge(&self, repo: &str, branch: &str, sha: &str, message: &str)
+             -> VolfResult<Option<String>> {
+        let source = format!("{}{}", MERGE_PREFIX, sha);
+        self.delete_branch(repo, &source)?; // left over from an earlier failed attempt
+        self.create_branch(repo, &source, sha)?;
+        let create = PullCreate {
+            head: &source,
+            base: branch,
+            title: message,
+        };
+        let data = self.write(Method::Post, &format!("repos/{}/pulls", repo), &create)?;
+        let pull: PullRequest = serde_json::from_str(&data)?;
+        let merge = self.merge_pull(repo, pull.number, sha, message, true, "merge");
+        if let Ok(None) = merge {
+            // gitea only removes the head branch of merged PRs
+            let _ = self.delete_branch(repo, &source);
+        }
+        merge
+    }
+
+    fn set_status(&self, repo: &str, sha: &str, context: &str, state: &str, description: &str)
+                  -> VolfResult<()> {
+        let status = StatusCreate {
+            state: state,
+            context: context,
+            description: description,
+        };
+        self.write(Method::Post, &format!("repos/{}/statuses/{}", repo, sha), &status)?;
+        Ok(())
+    }
+
+    fn comment(&self, repo: &str, num: u64, body: &str) -> VolfResult<()> {
+        let comment = CommentCreate { body: body };
+        self.write(Method::Post, &format!("repos/{}/issues/{}/comments", repo, num), &comment)?;
+        Ok(())
+    }
+
+    /// Merge the PR itself, as gitea can not fast-forward branches
+    ///
+    /// This lands the tree of the tested merge commit as long as the base has not
+    /// moved since testing started, which is checked before landing.
+    /// The merge styles of gitea are named like the strategies.
+    fn land(&self, repo: &str, num: u64, base: &str, head: &str, merge: &str,
+            strategy: MergeStrategy)
+            -> VolfResult<()> {
+        let message = format!("Auto merge of #{} into {}\n\nTested as {}", num, base, merge);
+        match self.merge_pull(repo, num, head, &message, false, strategy.name())? {
+            Some(_) => Ok(()),
+            None => Err(VolfError::Forge(format!("{}#{} can not be merged", repo, num))),
+        }
+    }
+}
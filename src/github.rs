@@ -0,0 +1,525 @@
+use std::io::Read;
+use std::sync::Arc;
+
+use hyper::Client;
+use hyper::method::Method;
+use hyper::status::StatusCode;
+use hyper::header::{Accept, Authorization, UserAgent, qitem};
+use hyper::mime::Mime;
+use serde::{Deserialize, Serialize};
+use serde_json;
+
+use super::{VolfError, VolfResult};
+use super::forge::{Forge, PullInfo, Comment, Status, FileContent};
+use super::github_app::AppAuth;
+use super::config::{Connection, GithubData, MergeStrategy};
+use super::net;
+
+// -----------------------------------------------------------------------------
+// Response types
+
+#[derive(Deserialize, Debug)]
+pub struct User {
+    /// Unique github user name
+    pub login: String,
+}
+
+/// A pull request review
+#[derive(Deserialize, Debug)]
+pub struct Review {
+    /// Reviewer
+    pub user: User,
+    /// Review summary (may contain commands)
+    pub body: Option<String>,
+    /// APPROVED / CHANGES_REQUESTED / COMMENTED
+    pub state: String,
+    /// Timestamp of submission (absent on pending reviews)
+    pub submitted_at: Option<String>,
+}
+
+/// A comment on the conversation of a PR
+#[derive(Deserialize, Debug)]
+struct IssueComment {
+    user: User,
+    body: String,
+    created_at: String,
+}
+
+/// An event on the timeline of a PR
+#[derive(Deserialize, Debug)]
+struct IssueEvent {
+    event: String,
+    created_at: String,
+}
+
+/// Head or base of a PR
+#[derive(Deserialize, Debug)]
+struct PullRef {
+    #[serde(rename = "ref")]
+    git_ref: String,
+    label: String,
+    sha: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct PullRequest {
+    number: u64,
+    title: String,
+    user: User,
+    head: PullRef,
+    base: PullRef,
+}
+
+#[derive(Deserialize, Debug)]
+struct RepositoryName {
+    full_name: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct RepositoryInfo {
+    default_branch: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct PullMergeable {
+    mergeable: Option<bool>,
+}
+
+#[derive(Deserialize, Debug)]
+struct CommitAuthor {
+    date: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct CommitData {
+    committer: CommitAuthor,
+}
+
+#[derive(Deserialize, Debug)]
+struct Commit {
+    commit: CommitData,
+}
+
+#[derive(Deserialize, Debug)]
+struct GitObject {
+    sha: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct GitRef {
+    object: GitObject,
+}
+
+// request bodies
+
+#[derive(Serialize)]
+struct RefUpdate<'a> {
+    sha: &'a str,
+    force: bool,
+}
+
+#[derive(Serialize)]
+struct RefCreate<'a> {
+    #[serde(rename = "ref")]
+    git_ref: String,
+    sha: &'a str,
+}
+
+#[derive(Serialize)]
+struct MergeCreate<'a> {
+    base: &'a str,
+    head: &'a str,
+    commit_message: &'a str,
+}
+
+#[derive(Serialize)]
+struct PullMerge<'a> {
+    commit_title: &'a str,
+    /// Only merge if the head is still at this changeset
+    sha: &'a str,
+    merge_method: &'a str,
+}
+
+#[derive(Serialize)]
+struct StatusCreate<'a> {
+    state: &'a str,
+    context: &'a str,
+    description: &'a str,
+}
+
+#[derive(Serialize)]
+struct CommentCreate<'a> {
+    body: &'a str,
+}
+
+// -----------------------------------------------------------------------------
+
+/// How volf authenticates against github
+#[derive(Clone)]
+pub enum GithubAuth {
+    /// Personal access token of the volf user
+    Token(String),
+    /// Installation tokens of a github app
+    App(Arc<AppAuth>),
+}
+
+impl GithubAuth {
+    /// Token to act on a repository with
+    pub fn token(&self, repo: &str) -> VolfResult<String> {
+        match *self {
+            GithubAuth::Token(ref token) => Ok(token.clone()),
+            GithubAuth::App(ref app) => app.token(repo),
+        }
+    }
+}
+
+/// Thin github client for the endpoints volf needs
+pub struct Api {
+    /// Api root
+    host: String,
+    /// Source of the token sent with every request
+    auth: GithubAuth,
+    client: Client,
+}
+
+impl Api {
+    pub fn new(host: &str, auth: GithubAuth, client: Client) -> Api {
+        Api {
+            host: host.trim_right_matches('/').into(),
+            auth: auth,
+            client: client,
+        }
+    }
+
+    fn request(&self, method: Method, uri: &str, body: Option<&str>)
+               -> VolfResult<(StatusCode, String)> {
+        // reviews still require the preview media type
+        let preview: Mime = "application/vnd.github.black-cat-preview+json".parse().unwrap();
+        let url = format!("{}/{}", self.host, uri);
+        let token = self.auth.token(&repo_of(uri))?;
+        let mut req = self.client
+            .request(method, &url)
+            .header(Authorization(format!("token {}", token)))
+            .header(UserAgent(format!("volf/{}", env!("CARGO_PKG_VERSION"))))
+            .header(Accept(vec![qitem(preview)]));
+        if let Some(body) = body {
+            req = req.body(body);
+        }
+        let mut res = req.send()?;
+        let mut data = String::new();
+        res.read_to_string(&mut data)?;
+        Ok((res.status, data))
+    }
+
+    fn get<D: Deserialize>(&self, uri: &str) -> VolfResult<D> {
+        let (_, data) = self.request(Method::Get, uri, None)?;
+        Ok(serde_json::from_str(&data)?)
+    }
+
+    /// Send a json body and return the response status and body
+    fn send<S: Serialize>(&self, method: Method, uri: &str, body: &S)
+                          -> VolfResult<(StatusCode, String)> {
+        let body = serde_json::to_string(body)?;
+        self.request(method, uri, Some(&body))
+    }
+
+    /// Send a json body and fail unless the response is a success
+    fn write<S: Serialize>(&self, method: Method, uri: &str, body: &S) -> VolfResult<String> {
+        let (status, data) = self.send(method, uri, body)?;
+        if !status.is_success() {
+            return Err(VolfError::Forge(format!("{} {}: {}", uri, status, data)));
+        }
+        Ok(data)
+    }
+
+    /// Every page of a listing, 100 entries at a time
+    fn get_all<D: Deserialize>(&self, uri: &str) -> VolfResult<Vec<D>> {
+        let sep = if uri.contains('?') { '&' } else { '?' };
+        let mut all = vec![];
+        for page in 1.. {
+            let entries: Vec<D> = self.get(&format!("{}{}per_page=100&page={}", uri, sep, page))?;
+            let n = entries.len();
+            all.extend(entries);
+            if n < 100 {
+                break;
+            }
+        }
+        Ok(all)
+    }
+
+    /// Repositories the token can access
+    pub fn repositories(&self) -> VolfResult<Vec<String>> {
+        let repos: Vec<RepositoryName> = self.get_all("user/repos")?;
+        Ok(repos.into_iter().map(|r| r.full_name).collect())
+    }
+
+    /// Default branch of a repository
+    pub fn default_branch(&self, repo: &str) -> VolfResult<String> {
+        let info: RepositoryInfo = self.get(&format!("repos/{}", repo))?;
+        Ok(info.default_branch)
+    }
+
+    /// Comments on the conversation of a PR
+    pub fn comments(&self, repo: &str, num: u64) -> VolfResult<Vec<Comment>> {
+        let comments: Vec<IssueComment> =
+            self.get_all(&format!("repos/{}/issues/{}/comments", repo, num))?;
+        Ok(comments.into_iter()
+            .map(|c| {
+                Comment {
+                    user: c.user.login,
+                    body: c.body,
+                    created_at: c.created_at,
+                }
+            })
+            .collect())
+    }
+
+    /// Every open PR of a repository
+    pub fn open_pulls(&self, repo: &str) -> VolfResult<Vec<PullInfo>> {
+        let pulls: Vec<PullRequest> = self.get_all(&format!("repos/{}/pulls?state=open", repo))?;
+        Ok(pulls.into_iter()
+            .map(|p| {
+                PullInfo {
+                    number: p.number,
+                    base: p.base.git_ref,
+                    head_sha: p.head.sha,
+                    head_label: p.head.label,
+                    author: p.user.login,
+                    title: p.title,
+                }
+            })
+            .collect())
+    }
+
+    /// Reviews on a PR in submission order
+    pub fn reviews(&self, repo: &str, num: u64) -> VolfResult<Vec<Review>> {
+        self.get_all(&format!("repos/{}/pulls/{}/reviews", repo, num))
+    }
+
+    /// Time of the last force-push to a PR, if any
+    pub fn force_pushed_at(&self, repo: &str, num: u64) -> VolfResult<Option<String>> {
+        let events: Vec<IssueEvent> =
+            self.get_all(&format!("repos/{}/issues/{}/events", repo, num))?;
+        Ok(events.into_iter()
+            .filter(|e| e.event == "head_ref_force_pushed")
+            .map(|e| e.created_at)
+            .max())
+    }
+
+    /// Statuses on a changeset, most recent first
+    pub fn statuses(&self, repo: &str, sha: &str) -> VolfResult<Vec<Status>> {
+        self.get(&format!("repos/{}/commits/{}/statuses?per_page=100", repo, sha))
+    }
+
+    /// Whether a PR merges cleanly into its base (None while github is computing it)
+    pub fn mergeable(&self, repo: &str, num: u64) -> VolfResult<Option<bool>> {
+        let pull: PullMergeable = self.get(&format!("repos/{}/pulls/{}", repo, num))?;
+        Ok(pull.mergeable)
+    }
+
+    /// Committer timestamp of a changeset
+    pub fn commit_date(&self, repo: &str, sha: &str) -> VolfResult<String> {
+        let commit: Commit = self.get(&format!("repos/{}/commits/{}", repo, sha))?;
+        Ok(commit.commit.committer.date)
+    }
+
+    /// Changeset a branch points to
+    pub fn branch_head(&self, repo: &str, branch: &str) -> VolfResult<String> {
+        let gitref: GitRef = self.get(&format!("repos/{}/git/refs/heads/{}", repo, branch))?;
+        Ok(gitref.object.sha)
+    }
+
+    /// Contents of a file on a branch or at a changeset (None if it does not exist)
+    pub fn file(&self, repo: &str, git_ref: &str, path: &str) -> VolfResult<Option<String>> {
+        let uri = format!("repos/{}/contents/{}?ref={}", repo, path, git_ref);
+        let (status, data) = self.request(Method::Get, &uri, None)?;
+        match status {
+            StatusCode::NotFound => Ok(None),
+            s if s.is_success() => {
+                let file: FileContent = serde_json::from_str(&data)?;
+                file.decode().map(Some)
+            }
+            s => Err(VolfError::Forge(format!("{} {}: {}", uri, s, data))),
+        }
+    }
+
+    /// Move a branch, creating it if it does not exist
+    pub fn set_branch(&self, repo: &str, branch: &str, sha: &str, force: bool) -> VolfResult<()> {
+        let update = RefUpdate {
+            sha: sha,
+            force: force,
+        };
+        let uri = format!("repos/{}/git/refs/heads/{}", repo, branch);
+        let (status, data) = self.send(Method::Patch, &uri, &update)?;
+        match status {
+            s if s.is_success() => Ok(()),
+            // github answers 422 for both missing refs and rejected non-fast-forwards
+            StatusCode::UnprocessableEntity if force => {
+                let create = RefCreate {
+                    git_ref: format!("refs/heads/{}", branch),
+                    sha: sha,
+                };
+                self.write(Method::Post, &format!("repos/{}/git/refs", repo), &create)?;
+                Ok(())
+            }
+            s => Err(VolfError::Forge(format!("{} {}: {}", uri, s, data))),
+        }
+    }
+
+    /// Merge a PR with the merge api unless its head moved from `sha`
+    pub fn merge_pull(&self, repo: &str, num: u64, sha: &str, title: &str, method: &str)
+                      -> VolfResult<()> {
+        let merge = PullMerge {
+            commit_title: title,
+            sha: sha,
+            merge_method: method,
+        };
+        self.write(Method::Put, &format!("repos/{}/pulls/{}/merge", repo, num), &merge)?;
+        Ok(())
+    }
+
+    /// Merge a changeset into a branch, returning the merge commit unless it conflicts
+    pub fn merge(&self, repo: &str, branch: &str, sha: &str, message: &str)
+                 -> VolfResult<Option<String>> {
+        let merge = MergeCreate {
+            base: branch,
+            head: sha,
+            commit_message: message,
+        };
+        let uri = format!("repos/{}/merges", repo);
+        let (status, data) = self.send(Method::Post, &uri, &merge)?;
+        match status {
+            StatusCode::Created => {
+                let commit: GitObject = serde_json::from_str(&data)?;
+                Ok(Some(commit.sha))
+            }
+            StatusCode::Conflict => Ok(None),
+            // nothing to merge - the branch already contains the changeset
+            StatusCode::NoContent => self.branch_head(repo, branch).map(Some),
+            s => Err(VolfError::Forge(format!("{} {}: {}", uri, s, data))),
+        }
+    }
+
+    /// Set a commit status on a changeset
+    pub fn set_status(&self, repo: &str, sha: &str, context: &str, state: &str, description: &str)
+                      -> VolfResult<()> {
+        let status = StatusCreate {
+            state: state,
+            context: context,
+            description: description,
+        };
+        self.write(Method::Post, &format!("repos/{}/statuses/{}", repo, sha), &status)?;
+        Ok(())
+    }
+
+    /// Post a comment on a PR
+    pub fn comment(&self, repo: &str, num: u64, body: &str) -> VolfResult<()> {
+        let comment = CommentCreate { body: body };
+        self.write(Method::Post, &format!("repos/{}/issues/{}/comments", repo, num), &comment)?;
+        Ok(())
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Owner and name of the repository an api path (repos/owner/name/..) refers to
+fn repo_of(uri: &str) -> String {
+    uri.split('/').skip(1).take(2).collect::<Vec<_>>().join("/")
+}
+
+/// Forge implementation for github.com or a github enterprise server
+pub struct GithubForge {
+    api: Api,
+}
+
+impl GithubForge {
+    pub fn new(auth: GithubAuth, github: &GithubData, conn: &Connection)
+               -> VolfResult<GithubForge> {
+        Ok(GithubForge { api: Api::new(&github.api_url, auth, net::client(conn)?) })
+    }
+}
+
+impl Forge for GithubForge {
+    fn repositories(&self) -> VolfResult<Vec<String>> {
+        self.api.repositories()
+    }
+
+    fn default_branch(&self, repo: &str) -> VolfResult<String> {
+        self.api.default_branch(repo)
+    }
+
+    fn open_pulls(&self, repo: &str) -> VolfResult<Vec<PullInfo>> { self.api.open_pulls(repo) }
+
+    fn comments(&self, repo: &str, num: u64) -> VolfResult<Vec<Comment>> {
+        let mut res = self.api.comments(repo, num)?;
+        for review in self.api.reviews(repo, num)? {
+            if let (Some(at), Some(body)) = (review.submitted_at, review.body) {
+                res.push(Comment {
+                    user: review.user.login,
+                    body: body,
+                    created_at: at,
+                });
+            }
+        }
+        Ok(res)
+    }
+
+    fn statuses(&self, repo: &str, sha: &str) -> VolfResult<Vec<Status>> {
+        self.api.statuses(repo, sha)
+    }
+
+    /// Github records force-pushes on the timeline, other pushes only by commit date
+    fn pushed_at(&self, repo: &str, num: u64, sha: &str) -> VolfResult<String> {
+        let committed = self.api.commit_date(repo, sha)?;
+        Ok(match self.api.force_pushed_at(repo, num)? {
+            Some(forced) if forced > committed => forced,
+            _ => committed,
+        })
+    }
+
+    fn mergeable(&self, repo: &str, num: u64) -> VolfResult<Option<bool>> {
+        self.api.mergeable(repo, num)
+    }
+
+    fn branch_head(&self, repo: &str, branch: &str) -> VolfResult<String> {
+        self.api.branch_head(repo, branch)
+    }
+
+    fn file(&self, repo: &str, git_ref: &str, path: &str) -> VolfResult<Option<String>> {
+        self.api.file(repo, git_ref, path)
+    }
+
+    fn set_branch(&self, repo: &str, branch: &str, sha: &str, force: bool) -> VolfResult<()> {
+        self.api.set_branch(repo, branch, sha, force)
+    }
+
+    fn merge(&self, repo: &str, branch: &str, sha: &str, message: &str)
+             -> VolfResult<Option<String>> {
+        self.api.merge(repo, branch, sha, message)
+    }
+
+    fn set_status(&self, repo: &str, sha: &str, context: &str, state: &str, description: &str)
+                  -> VolfResult<()> {
+        self.api.set_status(repo, sha, context, state, description)
+    }
+
+    fn comment(&self, repo: &str, num: u64, body: &str) -> VolfResult<()> {
+        self.api.comment(repo, num, body)
+    }
+
+    /// Merges fast-forward the base to the tested merge commit
+    ///
+    /// Squashes and rebases go through the merge api at the tested head. They land the
+    /// tested tree as long as the base has not moved, which is checked before landing.
+    fn land(&self, repo: &str, num: u64, base: &str, head: &str, merge: &str,
+            strategy: MergeStrategy)
+            -> VolfResult<()> {
+        match strategy {
+            MergeStrategy::Merge => self.api.set_branch(repo, base, merge, false),
+            _ => {
+                let title = format!("Auto merge of #{} into {}", num, base);
+                self.api.merge_pull(repo, num, head, &title, strategy.name())
+            }
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Read;
+use std::sync::Mutex;
+
+use base64;
+use hyper::Client;
+use hyper::method::Method;
+use hyper::status::StatusCode;
+use hyper::header::{Accept, Authorization, UserAgent, qitem};
+use hyper::mime::Mime;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::rsa::Rsa;
+use openssl::sign::Signer;
+use serde::Deserialize;
+use serde_json;
+use time;
+
+use super::{VolfError, VolfResult};
+use super::config::GithubApp;
+
+/// Seconds before expiry at which cached installation tokens are replaced
+pub const REFRESH_MARGIN: i64 = 5 * 60;
+
+/// Lifetime of app JWTs (github allows at most 10 minutes)
+const JWT_LIFETIME: i64 = 9 * 60;
+
+// -----------------------------------------------------------------------------
+// Response types
+
+#[derive(Deserialize, Debug)]
+struct AccessToken {
+    token: String,
+    /// ISO 8601 timestamp in UTC
+    expires_at: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Installation {
+    id: u64,
+}
+
+#[derive(Deserialize, Debug)]
+struct InstalledRepository {
+    full_name: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct InstalledRepositories {
+    repositories: Vec<InstalledRepository>,
+}
+
+#[derive(Serialize)]
+struct Claims {
+    /// Issued at (backdated for clock drift)
+    iat: i64,
+    /// Expiry
+    exp: i64,
+    /// The app id
+    iss: u64,
+}
+
+/// An installation access token and its expiry in seconds since the epoch
+struct Token {
+    token: String,
+    expires: i64,
+}
+
+// -----------------------------------------------------------------------------
+
+/// Authentication as a github app
+///
+/// Requests on a repository use a token of the installation containing it. Tokens
+/// are minted with a JWT signed by the app key, and cached until shortly before
+/// they expire.
+pub struct AppAuth {
+    /// Numeric id of the app
+    app_id: u64,
+    /// Private key of the app
+    key: PKey,
+    /// Api root
+    host: String,
+    client: Client,
+    /// Installation id of every installed repository
+    installations: Mutex<BTreeMap<String, u64>>,
+    /// Cached tokens keyed by installation id
+    tokens: Mutex<BTreeMap<u64, Token>>,
+}
+
+impl AppAuth {
+    pub fn new(app_id: u64, pem: &str, host: &str, client: Client) -> VolfResult<AppAuth> {
+        let rsa = Rsa::private_key_from_pem(pem.as_bytes())?;
+        Ok(AppAuth {
+            app_id: app_id,
+            key: PKey::from_rsa(rsa)?,
+            host: host.trim_right_matches('/').into(),
+            client: client,
+            installations: Mutex::new(BTreeMap::new()),
+            tokens: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    /// Load the private key of a configured app served from `host`
+    pub fn read(app: &GithubApp, host: &str, client: Client) -> VolfResult<AppAuth> {
+        let mut pem = String::new();
+        fs::File::open(&app.private_key)?.read_to_string(&mut pem)?;
+        AppAuth::new(app.app_id, &pem, host, client)
+    }
+
+    /// A JWT identifying the app itself
+    fn jwt(&self) -> VolfResult<String> {
+        let now = time::get_time().sec;
+        let claims = Claims {
+            iat: now - 60,
+            exp: now + JWT_LIFETIME,
+            iss: self.app_id,
+        };
+        let encode = |data: &[u8]| base64::encode_config(data, base64::URL_SAFE_NO_PAD);
+        let message = format!("{}.{}",
+                              encode(br#"{"alg":"RS256","typ":"JWT"}"#),
+                              encode(serde_json::to_string(&claims)?.as_bytes()));
+        let mut signer = Signer::new(MessageDigest::sha256(), &self.key)?;
+        signer.update(message.as_bytes())?;
+        Ok(format!("{}.{}", message, encode(&signer.finish()?)))
+    }
+
+    fn request(&self, method: Method, uri: &str, auth: String)
+               -> VolfResult<(StatusCode, String)> {
+        // app endpoints still require the preview media type
+        let preview: Mime = "application/vnd.github.machine-man-preview+json".parse().unwrap();
+        let url = format!("{}/{}", self.host, uri);
+        let mut res = self.client
+            .request(method, &url)
+            .header(Authorization(auth))
+            .header(UserAgent(format!("volf/{}", env!("CARGO_PKG_VERSION"))))
+            .header(Accept(vec![qitem(preview)]))
+            .send()?;
+        let mut data = String::new();
+        res.read_to_string(&mut data)?;
+        if !res.status.is_success() {
+            return Err(VolfError::Forge(format!("{} {}: {}", uri, res.status, data)));
+        }
+        Ok((res.status, data))
+    }
+
+    fn get<D: Deserialize>(&self, uri: &str, auth: String) -> VolfResult<D> {
+        let (_, data) = self.request(Method::Get, uri, auth)?;
+        Ok(serde_json::from_str(&data)?)
+    }
+
+    /// Every page of a listing, 100 entries at a time
+    ///
+    /// `entries` takes the listed entries out of a page.
+    fn get_all<P, D, F>(&self, uri: &str, auth: &str, entries: F) -> VolfResult<Vec<D>>
+        where P: Deserialize,
+              F: Fn(P) -> Vec<D>
+    {
+        let mut all = vec![];
+        for page in 1.. {
+            let data: P = self.get(&format!("{}?per_page=100&page={}", uri, page), auth.into())?;
+            let listed = entries(data);
+            let n = listed.len();
+            all.extend(listed);
+            if n < 100 {
+                break;
+            }
+        }
+        Ok(all)
+    }
+
+    /// A token of an installation, minting a new one if the cached one expires soon
+    pub fn installation_token(&self, id: u64) -> VolfResult<String> {
+        let now = time::get_time().sec;
+        let mut tokens = self.tokens.lock().unwrap();
+        if let Some(t) = tokens.get(&id) {
+            if t.expires - now > REFRESH_MARGIN {
+                return Ok(t.token.clone());
+            }
+        }
+        debug!("Refreshing token of installation {}", id);
+        let uri = format!("installations/{}/access_tokens", id);
+        let (_, data) = self.request(Method::Post, &uri, format!("Bearer {}", self.jwt()?))?;
+        let access: AccessToken = serde_json::from_str(&data)?;
+        let expires = time::strptime(&access.expires_at, "%Y-%m-%dT%H:%M:%SZ")
+            .map(|tm| tm.to_timespec().sec)
+            .unwrap_or(now + 60 * 60);
+        tokens.insert(id,
+                      Token {
+                          token: access.token.clone(),
+                          expires: expires,
+                      });
+        Ok(access.token)
+    }
+
+    /// A token to act on a repository with
+    pub fn token(&self, repo: &str) -> VolfResult<String> {
+        let id = self.installations.lock().unwrap().get(repo).cloned();
+        match id {
+            Some(id) => self.installation_token(id),
+            None => Err(VolfError::Forge(format!("app is not installed on {}", repo))),
+        }
+    }
+
+    /// Track repositories added to an installation
+    pub fn install(&self, id: u64, repos: &[String]) {
+        let mut installations = self.installations.lock().unwrap();
+        for repo in repos {
+            installations.insert(repo.clone(), id);
+        }
+    }
+
+    /// Forget repositories removed from an installation
+    pub fn uninstall(&self, repos: &[String]) {
+        let mut installations = self.installations.lock().unwrap();
+        for repo in repos {
+            installations.remove(repo);
+        }
+    }
+
+    /// Forget an installation, returning the repositories it contained
+    pub fn remove_installation(&self, id: u64) -> Vec<String> {
+        self.tokens.lock().unwrap().remove(&id);
+        let mut installations = self.installations.lock().unwrap();
+        let repos = installations.iter()
+            .filter(|&(_, &i)| i == id)
+            .map(|(r, _)| r.clone())
+            .collect::<Vec<_>>();
+        for repo in &repos {
+            installations.remove(repo);
+        }
+        repos
+    }
+
+    /// Whether the app is installed on a repository
+    pub fn installed(&self, repo: &str) -> bool {
+        self.installations.lock().unwrap().contains_key(repo)
+    }
+
+    /// Every repository the app is installed on
+    pub fn repositories(&self) -> Vec<String> {
+        self.installations.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Find the repositories of every installation of the app
+    pub fn discover(&self) -> VolfResult<usize> {
+        let jwt = format!("Bearer {}", self.jwt()?);
+        let installs = self.get_all("app/installations", &jwt, |i: Vec<Installation>| i)?;
+        for install in installs {
+            let token = format!("token {}", self.installation_token(install.id)?);
+            let listed = |page: InstalledRepositories| -> Vec<String> {
+                page.repositories.into_iter().map(|r| r.full_name).collect()
+            };
+            let repos = self.get_all("installation/repositories", &token, listed)?;
+            info!("Installation {} covers {} repositories", install.id, repos.len());
+            self.install(install.id, &repos);
+        }
+        Ok(self.repositories().len())
+    }
+}
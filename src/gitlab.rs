@@ -0,0 +1,449 @@
+use std::io::Read;
+
+use hyper::Client;
+use hyper::method::Method;
+use hyper::status::StatusCode;
+use hyper::header::{ContentType, UserAgent};
+use serde::{Deserialize, Serialize};
+use serde_json;
+use url::form_urlencoded::byte_serialize;
+
+use super::{VolfError, VolfResult};
+use super::forge::{Forge, PullInfo, Comment, Status, FileContent, MERGE_PREFIX};
+use super::config::MergeStrategy;
+
+/// private token of the volf user
+header! {(PrivateToken, "PRIVATE-TOKEN") => [String]}
+
+// -----------------------------------------------------------------------------
+// Response types
+
+#[derive(Deserialize, Debug)]
+struct User {
+    username: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct ProjectName {
+    path_with_namespace: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Project {
+    default_branch: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct MergeRequest {
+    iid: u64,
+    title: String,
+    sha: String,
+    source_branch: String,
+    target_branch: String,
+    author: User,
+    #[serde(default)]
+    merge_status: String,
+    #[serde(default)]
+    merge_commit_sha: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Note {
+    body: String,
+    author: User,
+    created_at: String,
+    /// Notes generated by gitlab (pushes, label changes, ..)
+    #[serde(default)]
+    system: bool,
+}
+
+/// A diff version of a merge request, created by every push
+#[derive(Deserialize, Debug)]
+struct Version {
+    head_commit_sha: String,
+    created_at: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct CommitStatus {
+    name: String,
+    status: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Commit {
+    id: String,
+    committed_date: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Branch {
+    commit: Commit,
+}
+
+// request bodies
+
+#[derive(Serialize)]
+struct BranchCreate<'a> {
+    branch: &'a str,
+    #[serde(rename = "ref")]
+    git_ref: &'a str,
+}
+
+#[derive(Serialize)]
+struct MergeRequestCreate<'a> {
+    source_branch: &'a str,
+    target_branch: &'a str,
+    title: &'a str,
+}
+
+#[derive(Serialize)]
+struct MergeRequestMerge<'a> {
+    merge_commit_message: &'a str,
+    should_remove_source_branch: bool,
+    squash: bool,
+    /// Only merge if the source is still at this changeset
+    sha: &'a str,
+}
+
+#[derive(Serialize)]
+struct StatusCreate<'a> {
+    state: &'a str,
+    name: &'a str,
+    description: &'a str,
+}
+
+#[derive(Serialize)]
+struct NoteCreate<'a> {
+    body: &'a str,
+}
+
+/// A merge request of `repo` as a PR
+fn pull_info(repo: &str, mr: MergeRequest) -> PullInfo {
+    let owner = repo.split('/').next().unwrap_or("");
+    PullInfo {
+        number: mr.iid,
+        title: mr.title,
+        head_sha: mr.sha,
+        head_label: format!("{}:{}", owner, mr.source_branch),
+        base: mr.target_branch,
+        author: mr.author.username,
+    }
+}
+
+/// Url path segment for a project path or branch name
+fn encode(s: &str) -> String { byte_serialize(s.as_bytes()).collect() }
+
+// -----------------------------------------------------------------------------
+
+/// Forge implementation for a gitlab instance (api v4)
+///
+/// Repositories are named by their project path (group/project) and PRs are merge
+/// requests numbered by their iid.
+pub struct GitlabForge {
+    /// Api root
+    host: String,
+    /// Token sent with every request
+    token: String,
+    client: Client,
+}
+
+impl GitlabForge {
+    pub fn new(url: &str, token: &str, client: Client) -> GitlabForge {
+        GitlabForge {
+            host: format!("{}/api/v4", url.trim_right_matches('/')),
+            token: token.into(),
+            client: client,
+        }
+    }
+
+    fn request(&self, method: Method, uri: &str, body: Option<&str>)
+               -> VolfResult<(StatusCode, String)> {
+        let url = format!("{}/{}", self.host, uri);
+        let mut req = self.client
+            .request(method, &url)
+            .header(PrivateToken(self.token.clone()))
+            .header(UserAgent(format!("volf/{}", env!("CARGO_PKG_VERSION"))))
+            .header(ContentType::json());
+        if let Some(body) = body {
+            req = req.body(body);
+        }
+        let mut res = req.send()?;
+        let mut data = String::new();
+        res.read_to_string(&mut data)?;
+        Ok((res.status, data))
+    }
+
+    fn get<D: Deserialize>(&self, uri: &str) -> VolfResult<D> {
+        let (status, data) = self.request(Method::Get, uri, None)?;
+        if !status.is_success() {
+            return Err(VolfError::Forge(format!("{} {}: {}", uri, status, data)));
+        }
+        Ok(serde_json::from_str(&data)?)
+    }
+
+    fn send<S: Serialize>(&self, method: Method, uri: &str, body: &S)
+                          -> VolfResult<(StatusCode, String)> {
+        let body = serde_json::to_string(body)?;
+        self.request(method, uri, Some(&body))
+    }
+
+    fn write<S: Serialize>(&self, method: Method, uri: &str, body: &S) -> VolfResult<String> {
+        let (status, data) = self.send(method, uri, body)?;
+        if !status.is_success() {
+            return Err(VolfError::Forge(format!("{} {}: {}", uri, status, data)));
+        }
+        Ok(data)
+    }
+
+    /// Every page of a listing, 100 entries at a time
+    fn get_all<D: Deserialize>(&self, uri: &str) -> VolfResult<Vec<D>> {
+        let sep = if uri.contains('?') { '&' } else { '?' };
+        let mut all = vec![];
+        for page in 1.. {
+            let entries: Vec<D> =
+                self.get(&format!("{}{}per_page=100&page={}", uri, sep, page))?;
+            let n = entries.len();
+            all.extend(entries);
+            if n < 100 {
+                break;
+            }
+        }
+        Ok(all)
+    }
+
+    fn project(repo: &str) -> String { format!("projects/{}", encode(repo)) }
+
+    fn merge_request(&self, repo: &str, num: u64) -> VolfResult<MergeRequest> {
+        self.get(&format!("{}/merge_requests/{}", GitlabForge::project(repo), num))
+    }
+
+    fn delete_branch(&self, repo: &str, branch: &str) -> VolfResult<()> {
+        let uri = format!("{}/repository/branches/{}", GitlabForge::project(repo), encode(branch));
+        let (status, data) = self.request(Method::Delete, &uri, None)?;
+        match status {
+            s if s.is_success() => Ok(()),
+            StatusCode::NotFound => Ok(()),
+            s => Err(VolfError::Forge(format!("{} {}: {}", uri, s, data))),
+        }
+    }
+
+    fn create_branch(&self, repo: &str, branch: &str, sha: &str) -> VolfResult<()> {
+        let create = BranchCreate {
+            branch: branch,
+            git_ref: sha,
+        };
+        let uri = format!("{}/repository/branches", GitlabForge::project(repo));
+        self.write(Method::Post, &uri, &create)?;
+        Ok(())
+    }
+
+    /// Merge a branch into another through a merge request and return the merge commit
+    ///
+    /// Returns None when gitlab refuses the merge (conflicts).
+    fn merge_branch(&self, repo: &str, source: &str, target: &str, sha: &str, message: &str)
+                    -> VolfResult<Option<String>> {
+        let project = GitlabForge::project(repo);
+        let create = MergeRequestCreate {
+            source_branch: source,
+            target_branch: target,
+            title: message,
+        };
+        let data = self.write(Method::Post, &format!("{}/merge_requests", project), &create)?;
+        let mr: MergeRequest = serde_json::from_str(&data)?;
+        self.merge_request_merge(repo, mr.iid, sha, message, true, false)
+    }
+
+    fn merge_request_merge(&self, repo: &str, num: u64, sha: &str, message: &str, remove: bool,
+                           squash: bool)
+                           -> VolfResult<Option<String>> {
+        let merge = MergeRequestMerge {
+            merge_commit_message: message,
+            should_remove_source_branch: remove,
+            squash: squash,
+            sha: sha,
+        };
+        let uri = format!("{}/merge_requests/{}/merge", GitlabForge::project(repo), num);
+        let (status, data) = self.send(Method::Put, &uri, &merge)?;
+        match status {
+            s if s.is_success() => {
+                let mr: MergeRequest = serde_json::from_str(&data)?;
+                Ok(mr.merge_commit_sha)
+            }
+            // 405 - cannot be merged, 406 - conflicts
+            StatusCode::MethodNotAllowed | StatusCode::NotAcceptable => Ok(None),
+            s => Err(VolfError::Forge(format!("{} {}: {}", uri, s, data))),
+        }
+    }
+}
+
+impl Forge for GitlabForge {
+    fn repositories(&self) -> VolfResult<Vec<String>> {
+        let projects: Vec<ProjectName> = self.get_all("projects?membership=true&simple=true")?;
+        Ok(projects.into_iter().map(|p| p.path_with_namespace).collect())
+    }
+
+    fn default_branch(&self, repo: &str) -> VolfResult<String> {
+        let project: Project = self.get(&GitlabForge::project(repo))?;
+        Ok(project.default_branch)
+    }
+
+    fn open_pulls(&self, repo: &str) -> VolfResult<Vec<PullInfo>> {
+        let uri = format!("{}/merge_requests?state=opened", GitlabForge::project(repo));
+        let mrs: Vec<MergeRequest> = self.get_all(&uri)?;
+        Ok(mrs.into_iter()
+            .filter(|mr| !mr.source_branch.starts_with(MERGE_PREFIX))
+            .map(|mr| pull_info(repo, mr))
+            .collect())
+    }
+
+    fn pull(&self, repo: &str, num: u64) -> VolfResult<PullInfo> {
+        Ok(pull_info(repo, self.merge_request(repo, num)?))
+    }
+
+    fn comments(&self, repo: &str, num: u64) -> VolfResult<Vec<Comment>> {
+        let uri = format!("{}/merge_requests/{}/notes", GitlabForge::project(repo), num);
+        let notes: Vec<Note> = self.get_all(&uri)?;
+        Ok(notes.into_iter()
+            .filter(|n| !n.system)
+            .map(|n| {
+                Comment {
+                    user: n.author.username,
+                    body: n.body,
+                    created_at: n.created_at,
+                }
+            })
+            .collect())
+    }
+
+    fn statuses(&self, repo: &str, sha: &str) -> VolfResult<Vec<Status>> {
+        let uri = format!("{}/repository/commits/{}/statuses", GitlabForge::project(repo), sha);
+        let statuses: Vec<CommitStatus> = self.get(&uri)?;
+        Ok(statuses.into_iter()
+            .map(|s| {
+                let state = match s.status.as_ref() {
+                    "success" => "success",
+                    "failed" => "failure",
+                    "canceled" => "error",
+                    _ => "pending",
+                };
+                Status {
+                    context: s.name,
+                    state: state.into(),
+                }
+            })
+            .collect())
+    }
+
+    /// Gitlab creates a diff version of the merge request on every push
+    fn pushed_at(&self, repo: &str, num: u64, sha: &str) -> VolfResult<String> {
+        let uri = format!("{}/merge_requests/{}/versions", GitlabForge::project(repo), num);
+        let versions: Vec<Version> = self.get_all(&uri)?;
+        if let Some(at) = versions.into_iter()
+            .filter(|v| v.head_commit_sha == sha)
+            .map(|v| v.created_at)
+            .max() {
+            return Ok(at);
+        }
+        let uri = format!("{}/repository/commits/{}", GitlabForge::project(repo), sha);
+        let commit: Commit = self.get(&uri)?;
+        Ok(commit.committed_date)
+    }
+
+    fn mergeable(&self, repo: &str, num: u64) -> VolfResult<Option<bool>> {
+        let mr = self.merge_request(repo, num)?;
+        Ok(match mr.merge_status.as_ref() {
+            "can_be_merged" => Some(true),
+            "cannot_be_merged" => Some(false),
+            _ => None,
+        })
+    }
+
+    fn branch_head(&self, repo: &str, branch: &str) -> VolfResult<String> {
+        let uri = format!("{}/repository/branches/{}", GitlabForge::project(repo), encode(branch));
+        let branch: Branch = self.get(&uri)?;
+        Ok(branch.commit.id)
+    }
+
+    fn file(&self, repo: &str, git_ref: &str, path: &str) -> VolfResult<Option<String>> {
+        let uri = format!("{}/repository/files/{}?ref={}",
+                          GitlabForge::project(repo),
+                          encode(path),
+                          encode(git_ref));
+        let (status, data) = self.request(Method::Get, &uri, None)?;
+        match status {
+            StatusCode::NotFound => Ok(None),
+            s if s.is_success() => {
+                let file: FileContent = serde_json::from_str(&data)?;
+                file.decode().map(Some)
+            }
+            s => Err(VolfError::Forge(format!("{} {}: {}", uri, s, data))),
+        }
+    }
+
+    /// Gitlab can not move branches, so forced moves recreate the branch
+    fn set_branch(&self, repo: &str, branch: &str, sha: &str, force: bool) -> VolfResult<()> {
+        if !force {
+            return Err(VolfError::Forge(format!("gitlab can not fast-forward {}", branch)));
+        }
+        self.delete_branch(repo, branch)?;
+        self.create_branch(repo, branch, sha)
+    }
+
+    fn merge(&self, repo: &str, branch: &str, sha: &str, message: &str)
+             -> VolfResult<Option<String>> {
+        let source = format!("{}{}", MERGE_PREFIX, sha);
+        self.delete_branch(repo, &source)?; // left over from an earlier failed attempt
+        self.create_branch(repo, &source, sha)?;
+        let merge = self.merge_branch(repo, &source, branch, sha, message);
+        if let Ok(None) = merge {
+            // gitlab only removes the source branch of merged requests
+            let _ = self.delete_branch(repo, &source);
+        }
+        merge
+    }
+
+    fn set_status(&self, repo: &str, sha: &str, context: &str, state: &str, description: &str)
+                  -> VolfResult<()> {
+        let state = match state {
+            "failure" | "error" => "failed",
+            s => s,
+        };
+        let status = StatusCreate {
+            state: state,
+            name: context,
+            description: description,
+        };
+        let uri = format!("{}/statuses/{}", GitlabForge::project(repo), sha);
+        self.write(Method::Post, &uri, &status)?;
+        Ok(())
+    }
+
+    fn comment(&self, repo: &str, num: u64, body: &str) -> VolfResult<()> {
+        let note = NoteCreate { body: body };
+        let uri = format!("{}/merge_requests/{}/notes", GitlabForge::project(repo), num);
+        self.write(Method::Post, &uri, &note)?;
+        Ok(())
+    }
+
+    /// Merge the merge request itself, as gitlab can not fast-forward branches
+    ///
+    /// This lands the tree of the tested merge commit as long as the base has not
+    /// moved since testing started, which is checked before landing.
+    ///
+    /// Squashing is an option of the merge. Gitlab only rebases through the merge method
+    /// of the project, so rebases are refused.
+    fn land(&self, repo: &str, num: u64, base: &str, head: &str, merge: &str,
+            strategy: MergeStrategy)
+            -> VolfResult<()> {
+        if strategy == MergeStrategy::Rebase {
+            return Err(VolfError::Forge(format!("gitlab can not rebase {}!{}", repo, num)));
+        }
+        let message = format!("Auto merge of !{} into {}\n\nTested as {}", num, base, merge);
+        let squash = strategy == MergeStrategy::Squash;
+        match self.merge_request_merge(repo, num, head, &message, false, squash)? {
+            Some(_) => Ok(()),
+            None => Err(VolfError::Forge(format!("{}!{} can not be merged", repo, num))),
+        }
+    }
+}
@@ -0,0 +1,404 @@
+use serde_json;
+use hyper::server::{Request, Response};
+use hyper::status::StatusCode;
+use std::io::Read;
+
+use super::{VolfResult, VolfError};
+use super::auth;
+use super::audit::Source;
+use super::config::{ForgeKind, REPO_FILE};
+use super::forge::{PullInfo, MERGE_PREFIX};
+use super::server::{ServerHandle, BuildResult};
+use super::webhook::PushCommit;
+
+// -----------------------------------------------------------------------------
+// Minor structs parts of various event types
+
+#[derive(Deserialize, Debug)]
+pub struct User {
+    /// Unique gitlab user name
+    pub username: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Project {
+    /// Group and project name joined by a slash
+    pub path_with_namespace: String,
+    /// Url of the project page
+    #[serde(default)]
+    pub web_url: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Label {
+    /// Name of the label
+    pub title: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LastCommit {
+    /// Changeset id
+    pub id: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MergeRequestAttributes {
+    /// Merge request number within the project
+    pub iid: u64,
+    /// Title text
+    pub title: String,
+    /// Branch of the merge request
+    pub source_branch: String,
+    /// Branch the merge request targets
+    pub target_branch: String,
+    /// Head of the merge request
+    pub last_commit: LastCommit,
+    /// open/close/reopen/update/merge/approved/..
+    #[serde(default)]
+    pub action: Option<String>,
+    /// Previous head (only set on updates that pushed new changesets)
+    #[serde(default)]
+    pub oldrev: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LabelChange {
+    /// Labels before the update
+    pub previous: Vec<Label>,
+    /// Labels after the update
+    pub current: Vec<Label>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Changes {
+    /// Set if the merge request was retargeted
+    #[serde(default)]
+    pub target_branch: Option<serde_json::Value>,
+    /// Set if labels changed
+    #[serde(default)]
+    pub labels: Option<LabelChange>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NoteAttributes {
+    /// Text of the note
+    pub note: String,
+    /// MergeRequest/Issue/Commit/Snippet
+    pub noteable_type: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NoteMergeRequest {
+    /// Merge request number within the project
+    pub iid: u64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PipelineAttributes {
+    /// Branch the pipeline ran on
+    #[serde(rename = "ref")]
+    pub git_ref: String,
+    /// Changeset the pipeline ran on
+    pub sha: String,
+    /// pending/running/success/failed/canceled/skipped
+    pub status: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Job {
+    /// Job id
+    pub id: u64,
+    /// Name of the job (matched against required builds)
+    pub name: String,
+    /// created/pending/running/success/failed/canceled/skipped/manual
+    pub status: String,
+}
+
+/// Part of every handled event naming the project it is about
+#[derive(Deserialize, Debug)]
+pub struct ProjectEvent {
+    /// Project the event happened in
+    pub project: Project,
+}
+
+// -----------------------------------------------------------------------------
+// Main Event types handled
+
+/// Merge Request Hook
+#[derive(Deserialize, Debug)]
+pub struct MergeRequest {
+    /// User performing the action
+    pub user: User,
+    /// Project containing the merge request
+    pub project: Project,
+    /// All merge request related data
+    pub object_attributes: MergeRequestAttributes,
+    /// Labels after the action
+    #[serde(default)]
+    pub labels: Vec<Label>,
+    /// What changed on updates
+    #[serde(default)]
+    pub changes: Option<Changes>,
+}
+
+/// Note Hook
+#[derive(Deserialize, Debug)]
+pub struct Note {
+    /// Author of the note
+    pub user: User,
+    /// Project containing the noteable
+    pub project: Project,
+    /// The note itself
+    pub object_attributes: NoteAttributes,
+    /// Set for notes on merge requests
+    #[serde(default)]
+    pub merge_request: Option<NoteMergeRequest>,
+}
+
+/// Push Hook
+#[derive(Deserialize, Debug)]
+pub struct Push {
+    /// Full ref name (refs/heads/branch for branches)
+    #[serde(rename = "ref")]
+    pub git_ref: String,
+    /// Changeset id of last change pushed
+    pub after: String,
+    /// Username of the pusher
+    pub user_username: String,
+    /// Project pushed to
+    pub project: Project,
+    /// Commits pushed (with the files they changed)
+    #[serde(default)]
+    pub commits: Vec<PushCommit>,
+}
+
+/// Pipeline Hook
+#[derive(Deserialize, Debug)]
+pub struct Pipeline {
+    /// Pipeline data
+    pub object_attributes: PipelineAttributes,
+    /// Project the pipeline ran in
+    pub project: Project,
+    /// Jobs of the pipeline
+    #[serde(default)]
+    pub builds: Vec<Job>,
+}
+
+// -----------------------------------------------------------------------------
+// event handlers
+
+impl ServerHandle {
+    fn handle_gitlab_merge_request(&self, data: MergeRequest) -> VolfResult<()> {
+        let repo = &data.project.path_with_namespace;
+        let mr = &data.object_attributes;
+        if mr.source_branch.starts_with(MERGE_PREFIX) {
+            debug!("ignoring temporary merge request {}!{}", repo, mr.iid);
+            return Ok(());
+        }
+        match mr.action.as_ref().map_or("", |a| a) {
+            "open" | "reopen" => {
+                // the event only names the user reopening it, not the author
+                let author = self.forge(repo)
+                    .and_then(|f| f.pull(repo, mr.iid))
+                    .map(|p| p.author)
+                    .unwrap_or_else(|e| {
+                        warn!("Failed to look up the author of {}!{}: {}", repo, mr.iid, e);
+                        String::new()
+                    });
+                let owner = repo.split('/').next().unwrap_or("");
+                let pull = PullInfo {
+                    number: mr.iid,
+                    title: mr.title.clone(),
+                    head_sha: mr.last_commit.id.clone(),
+                    head_label: format!("{}:{}", owner, mr.source_branch),
+                    base: mr.target_branch.clone(),
+                    author: author,
+                };
+                self.open_pull(repo, &pull);
+                for label in &data.labels {
+                    self.update_pull(repo, mr.iid, |pr| self.apply_label(pr, &label.title, true));
+                }
+            }
+            "close" | "merge" => self.close_pull(repo, mr.iid),
+            "update" => {
+                let changes = data.changes.as_ref();
+                self.update_pull(repo, mr.iid, |pr| {
+                    pr.set_title(&mr.title);
+                    if mr.oldrev.is_some() {
+                        info!("{}!{} pushed to {}", repo, mr.iid, mr.last_commit.id);
+                        pr.set_head(&mr.last_commit.id);
+                    }
+                    if changes.map_or(false, |c| c.target_branch.is_some()) {
+                        info!("{}!{} retargeted to {}", repo, mr.iid, mr.target_branch);
+                        pr.set_base(&mr.target_branch);
+                    }
+                    if let Some(labels) = changes.and_then(|c| c.labels.as_ref()) {
+                        for l in &labels.previous {
+                            if !labels.current.iter().any(|c| c.title == l.title) {
+                                self.apply_label(pr, &l.title, false);
+                            }
+                        }
+                        for l in &labels.current {
+                            if !labels.previous.iter().any(|p| p.title == l.title) {
+                                self.apply_label(pr, &l.title, true);
+                            }
+                        }
+                    }
+                })
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn handle_gitlab_note(&self, data: Note) -> VolfResult<()> {
+        if let (Some(mr), "MergeRequest") =
+            (data.merge_request.as_ref(), data.object_attributes.noteable_type.as_ref()) {
+            debug!("Note on {}!{} by {} - {}",
+                   data.project.path_with_namespace,
+                   mr.iid,
+                   data.user.username,
+                   data.object_attributes.note);
+            self.comment_pull(&data.project.path_with_namespace,
+                              mr.iid,
+                              &data.user.username,
+                              &data.object_attributes.note,
+                              Source::Comment);
+        }
+        Ok(())
+    }
+
+    fn handle_gitlab_push(&self, data: Push) -> VolfResult<()> {
+        let repo = &data.project.path_with_namespace;
+        if !data.git_ref.starts_with("refs/heads/") {
+            debug!("ignoring push to {} in {}", data.git_ref, repo);
+            return Ok(());
+        }
+        let branch = &data.git_ref["refs/heads/".len()..];
+        if branch.starts_with(MERGE_PREFIX) {
+            return Ok(());
+        }
+        let changed_config = data.commits.iter().any(|c| c.touches(REPO_FILE));
+        self.push_branch(repo, branch, &data.after, &data.user_username, changed_config);
+        Ok(())
+    }
+
+    /// Turn a finished pipeline on a merge commit under test into build results
+    ///
+    /// Every successful job counts as a passed build of the same name,
+    /// and a pipeline that did not succeed fails the PR.
+    fn handle_gitlab_pipeline(&self, data: Pipeline) -> VolfResult<()> {
+        let repo = &data.project.path_with_namespace;
+        let pipeline = &data.object_attributes;
+        if !["success", "failed", "canceled"].contains(&pipeline.status.as_ref()) {
+            return Ok(()); // not finished
+        }
+        let num = {
+            let prs = self.prs.lock().unwrap();
+            prs.iter().find(|pr| &pr.repo == repo && pr.is_testing(&pipeline.sha)).map(|pr| pr.num)
+        };
+        let num = match num {
+            Some(n) => n,
+            None => {
+                debug!("ignoring pipeline on {} in {}", pipeline.git_ref, repo);
+                return Ok(());
+            }
+        };
+        let result = |name: Option<String>, url: Option<String>, success: bool| {
+            BuildResult {
+                repo: repo.clone(),
+                number: num,
+                sha: pipeline.sha.clone(),
+                success: success,
+                name: name,
+                url: url,
+            }
+        };
+        for job in data.builds.iter().filter(|j| j.status == "success") {
+            let url = format!("{}/-/jobs/{}", data.project.web_url, job.id);
+            self.build_result(result(Some(job.name.clone()), Some(url), true))?;
+        }
+        if pipeline.status != "success" {
+            self.build_result(result(None, None, false))?;
+        }
+        Ok(())
+    }
+
+    /// Gitlab event multiplexer
+    pub fn handle_gitlab_event(&self, event: &str, payload: &str) -> VolfResult<()> {
+        self.metrics.event(event);
+        match event {
+            "Merge Request Hook" => {
+                self.handle_gitlab_merge_request(serde_json::from_str(&payload)?)
+            }
+            "Note Hook" => self.handle_gitlab_note(serde_json::from_str(&payload)?),
+            "Push Hook" => self.handle_gitlab_push(serde_json::from_str(&payload)?),
+            "Pipeline Hook" => self.handle_gitlab_pipeline(serde_json::from_str(&payload)?),
+            _ => Err(VolfError::SpammyGithub(event.into())),
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// webhook server handler
+
+/// name of Gitlab event
+header! {(XGitlabEvent, "X-Gitlab-Event") => [String]}
+
+/// secret token configured on the webhook
+header! {(XGitlabToken, "X-Gitlab-Token") => [String]}
+
+/// unique id for each delivery (not sent by older gitlab versions)
+header! {(XGitlabEventUuid, "X-Gitlab-Event-UUID") => [String]}
+
+impl ServerHandle {
+    /// Check the webhook token, and that the project is tracked on gitlab
+    ///
+    /// The token is shared by every project, so it must not reach repositories
+    /// with the same name on other forges.
+    fn verify_gitlab(&self, expected: &str, token: &str, payload: &str) -> VolfResult<()> {
+        if !auth::token_eq(expected, token) {
+            return Err(VolfError::InvalidSignature("gitlab".into()));
+        }
+        let data: ProjectEvent = serde_json::from_str(payload)?;
+        let name = data.project.path_with_namespace;
+        match self.repository(&name) {
+            Some(ref r) if r.forge == ForgeKind::Gitlab => Ok(()),
+            _ => Err(VolfError::InvalidSignature(name)),
+        }
+    }
+
+    pub fn handle_gitlab_webhook(&self, mut req: Request, mut res: Response) {
+        let mut payload = String::new();
+        let headers = req.headers.clone();
+        let cfg = self.cfg();
+        let expected = cfg.gitlab.as_ref().map(|gl| &gl.webhook_token);
+        if let (Some(&XGitlabEvent(ref event)), Some(&XGitlabToken(ref token)), Some(expected)) =
+            (headers.get::<XGitlabEvent>(), headers.get::<XGitlabToken>(), expected) {
+            if let Ok(_) = req.read_to_string(&mut payload) {
+                debug!("gitlab event: {}", event);
+                if let Err(e) = self.verify_gitlab(expected, token, &payload) {
+                    warn!("Rejecting gitlab {}: {}", event, e);
+                    *res.status_mut() = StatusCode::Unauthorized;
+                    return;
+                }
+                // without a delivery id, a redelivery is the same event with the same payload
+                let id = match headers.get::<XGitlabEventUuid>() {
+                    Some(&XGitlabEventUuid(ref id)) => id.clone(),
+                    None => auth::digest(&format!("{}\n{}", event, payload)),
+                };
+                if self.record_delivery(ForgeKind::Gitlab, &id, event, &payload) {
+                    let _ = self.handle_gitlab_event(&event, &payload)
+                        .map_err(|err| warn!("Failed to handle {} : {}", event, err));
+                } else {
+                    info!("Ignoring redelivered {} {}", event, id);
+                }
+            }
+        } else {
+            warn!("Rejecting gitlab event without token");
+            *res.status_mut() = StatusCode::Unauthorized;
+            return;
+        }
+        res.send(b"ok").ok();
+    }
+}
@@ -1,9 +1,13 @@
 //! This is the rust doc for the `volf` *library* the github + jenkins interface
 //! that the `volf` binary relies on to maintain state.
 
+extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+#[macro_use]
 extern crate serde_json;
+extern crate serde_yaml;
+extern crate toml;
 
 #[macro_use]
 extern crate log;
@@ -12,16 +16,43 @@ extern crate log;
 extern crate hyper;
 
 extern crate hubcaps;
+extern crate hyper_native_tls;
+extern crate crypto;
+extern crate rand;
+extern crate url;
+extern crate time;
+extern crate openssl;
+extern crate native_tls;
+extern crate base64;
 
 // re-exports
 pub use errors::{VolfError, VolfResult};
-pub use pullrequest::{Pull, parse_commands};
+pub use pullrequest::{Pull, Progress, BuildLink, parse_commands};
 
 pub mod config;
 pub mod server;
+pub mod github;
+pub mod github_app;
+pub mod forge;
+pub mod gitlab;
+pub mod gitea;
+pub mod net;
+pub mod secret;
+pub mod validate;
 
 pub mod ci;
+pub mod auth;
 
 mod errors;
 mod webhook;
+mod gitlab_webhook;
+mod views;
+mod metrics;
+mod oauth;
+mod admin;
+mod reconcile;
+mod reload;
+mod repofile;
+mod eventlog;
+mod audit;
 mod pullrequest;
@@ -3,21 +3,26 @@ extern crate clap;
 #[macro_use]
 extern crate log;
 extern crate env_logger;
+extern crate chan_signal;
 
-extern crate hubcaps;
 extern crate hyper;
-extern crate hyper_native_tls;
+extern crate serde_json;
 
-use hyper::{Server, Client};
-use hyper::net::HttpsConnector;
-use hyper_native_tls::NativeTlsClient;
-use hubcaps::{Credentials, Github};
+use hyper::Server;
 
 
 extern crate volf;
-use volf::config::Config;
+use volf::{VolfError, VolfResult};
+use volf::config::{Config, ForgeKind};
 use volf::server::{ServerHandle, PullRequestState};
+use volf::github::{GithubForge, GithubAuth};
+use volf::github_app::AppAuth;
+use volf::gitlab::GitlabForge;
+use volf::gitea::GiteaForge;
+use volf::net;
+use volf::validate;
 
+use chan_signal::Signal;
 use clap::{Arg, App, AppSettings, SubCommand};
 use std::process;
 use std::sync::{Arc, Mutex};
@@ -35,6 +40,35 @@ fn result_exit<T, E>(name: &str, x: Result<T, E>)
     process::exit(0);
 }
 
+/// Personal github token from volf.json, falling back to GITHUB_TOKEN
+fn github_token(config: &Config) -> Option<String> {
+    if !config.github.access_token.is_empty() {
+        return Some(config.github.access_token.to_string());
+    }
+    env::var("GITHUB_TOKEN").ok()
+}
+
+/// Create a server with clients for every configured forge
+fn server(prs: PullRequestState, github: GithubAuth, config: Config) -> VolfResult<ServerHandle> {
+    let conn = config.connection.clone();
+    let gitlab = config.gitlab.clone();
+    let gitea = config.gitea.clone();
+    let forge = Arc::new(GithubForge::new(github.clone(), &config.github, &conn)?);
+    let mut srv = ServerHandle::new(prs, forge, Arc::new(config));
+    if let GithubAuth::App(app) = github {
+        srv.set_app(app);
+    }
+    if let Some(gl) = gitlab {
+        let gitlab = GitlabForge::new(&gl.url, &gl.access_token, net::client(&conn)?);
+        srv.add_forge(ForgeKind::Gitlab, Arc::new(gitlab));
+    }
+    if let Some(gt) = gitea {
+        let gitea = GiteaForge::new(&gt.url, &gt.access_token, net::client(&conn)?);
+        srv.add_forge(ForgeKind::Gitea, Arc::new(gitea));
+    }
+    Ok(srv)
+}
+
 fn main() {
     let args = App::new("volf")
         .about("Github webhook server and CI control bot")
@@ -44,6 +78,12 @@ fn main() {
         .setting(AppSettings::ColorAuto)
         .setting(AppSettings::DeriveDisplayOrder)
         .global_settings(&[AppSettings::ColoredHelp, AppSettings::ColorAuto])
+        .arg(Arg::with_name("config")
+            .short("c")
+            .long("config")
+            .takes_value(true)
+            .global(true)
+            .help("Config file, .json, .toml or .yaml (default $VOLF_CONFIG or volf.json)"))
         .subcommand(SubCommand::with_name("start")
             .about("Start volf server")
             .alias("run")
@@ -51,67 +91,153 @@ fn main() {
                 .short("s")
                 .long("synchronize")
                 .help("Re-synchronize github state before starting")))
+        .subcommand(SubCommand::with_name("replay")
+            .about("Replay a webhook event log against a fresh state and print the queue")
+            .arg(Arg::with_name("file")
+                .required(true)
+                .help("Event log written via event_log in volf.json")))
         .subcommand(SubCommand::with_name("config")
             .about("Generate or edit the local config")
             .subcommand(SubCommand::with_name("edit")
                 .about("Open the local config with $EDITOR"))
             .subcommand(SubCommand::with_name("generate")
-                .about("Generate an empty config in current directory")))
+                .about("Generate an empty config (in the format of its extension)"))
+            .subcommand(SubCommand::with_name("validate")
+                .about("Check the local config for mistakes"))
+            .subcommand(SubCommand::with_name("schema")
+                .about("Print the JSON schema of the config for editors")))
         .get_matches();
 
     env_logger::init().unwrap();
 
+    let cfg_path = Config::path(args.value_of("config"));
+
     if let Some(cfgargs) = args.subcommand_matches("config") {
         if let Some(_) = cfgargs.subcommand_matches("generate") {
-            result_exit("generate", Config::generate());
+            result_exit("generate", Config::generate(&cfg_path));
         }
         if let Some(_) = cfgargs.subcommand_matches("edit") {
-            result_exit("edit", Config::edit())
+            result_exit("edit", Config::edit(&cfg_path))
+        }
+        if let Some(_) = cfgargs.subcommand_matches("validate") {
+            let checked = Config::validate(&cfg_path).and_then(|problems| {
+                for p in &problems {
+                    println!("{}: {}", cfg_path.display(), p);
+                }
+                if !problems.is_empty() {
+                    return Err(VolfError::Config(format!("{} problems found", problems.len())));
+                }
+                info!("{} is valid", cfg_path.display());
+                Ok(())
+            });
+            result_exit("validate", checked);
+        }
+        if let Some(_) = cfgargs.subcommand_matches("schema") {
+            println!("{}", serde_json::to_string_pretty(&validate::schema()).unwrap());
+            process::exit(0);
         }
     }
 
     // Force config to exists before allowing remaining actions
-    let config = Config::read()
+    let config = Config::read(&cfg_path)
         .map_err(|e| {
             error!("Configuration error: {}", e);
-            println!("Ensure you have volf.json is valid");
+            println!("Ensure {} is valid", cfg_path.display());
             process::exit(1);
         })
         .unwrap();
 
-    // Create a github client from our credentials
-    // TODO: env secrets -> struct (there's a nice crate for it)
-    let token = env::var("GITHUB_TOKEN")
-        .map_err(|_| {
-            error!("Missing GITHUB_TOKEN environment variable");
-            process::exit(1)
-        })
-        .unwrap();
+    // Replay recorded events through a fresh state (event handlers stay offline)
+    if let Some(replayargs) = args.subcommand_matches("replay") {
+        let srv = ServerHandle::for_replay(&config);
+        let replayed = srv.replay(replayargs.value_of("file").unwrap())
+            .and_then(|n| {
+                info!("Replayed {} events", n);
+                srv.queue_json(None)
+            })
+            .map(|queue| println!("{}", queue));
+        result_exit("replay", replayed);
+    }
 
-    let github =
-        Arc::new(Github::new(format!("volf/{}", crate_version!()),
-                             Client::with_connector(HttpsConnector::new(NativeTlsClient::new()
-                                                                            .unwrap())),
-                             Credentials::Token(token)));
+    // Authenticate as the configured github app, or with a personal token
+    let github = if let Some(app) = config.github.app.as_ref() {
+        let auth = net::client(&config.connection)
+            .and_then(|client| AppAuth::read(app, &config.github.api_url, client))
+            .map_err(|e| {
+                error!("Failed to load github app key {}: {}", app.private_key, e);
+                process::exit(1)
+            })
+            .unwrap();
+        match auth.discover() {
+            Ok(n) => info!("Github app is installed on {} repositories", n),
+            Err(e) => error!("Failed to list github app installations: {}", e),
+        }
+        GithubAuth::App(Arc::new(auth))
+    } else {
+        let token = github_token(&config)
+            .ok_or_else(|| {
+                error!("Missing github.access_token in volf.json or GITHUB_TOKEN variable");
+                process::exit(1)
+            })
+            .unwrap();
+        GithubAuth::Token(token)
+    };
 
     // Application state is just a shared vector of PRs
     let prs: PullRequestState = Arc::new(Mutex::new(vec![]));
 
     let serverargs = args.subcommand_matches("start").unwrap();
-    // Synchronize state before starting the server if requested
-    if serverargs.is_present("synchronize") {
-        for repo in &config.repositories {
-            let _ = repo.synchronize(github.clone(), &repo.name);
-        }
-    }
+
+    // Subscribe to SIGHUP before spawning any threads
+    let hup = chan_signal::notify(&[Signal::HUP]);
 
     // Set up webhook server
     let port = config.port;
-    let srv = ServerHandle::new(prs.clone(), github, Arc::new(config));
-    // Start pull request queue thread first on the server object
-    //TODO: may be able to just run this off events in main webhook handler?
+    let mut srv = server(prs.clone(), github, config)
+        .map_err(|e| {
+            error!("Failed to set up forge clients: {}", e);
+            process::exit(1)
+        })
+        .unwrap();
+    srv.set_config_path(cfg_path.clone());
+
+    // Expand repository patterns against the repositories the tokens can see
+    if let Err(e) = srv.discover() {
+        error!("Failed to list repositories for patterns: {}", e);
+    }
+
+    // Synchronize state in the background if requested, and report ready after
+    if serverargs.is_present("synchronize") {
+        let srv_sync = srv.clone();
+        thread::spawn(move || {
+            let repos = srv_sync.repositories();
+            for repo in &repos {
+                let _ = srv_sync.synchronize(repo)
+                    .map_err(|e| error!("Failed to synchronize {}: {}", repo.name, e));
+            }
+            info!("Synchronized {} repositories", repos.len());
+            srv_sync.set_ready();
+        });
+    } else {
+        srv.set_ready();
+    }
+    // Reload the config on SIGHUP
+    let srv_hup = srv.clone();
+    thread::spawn(move || for _ in hup.iter() {
+        match srv_hup.reload() {
+            Ok(_) => info!("Reloaded {}", cfg_path.display()),
+            Err(e) => error!("Config reload failed, keeping the running config: {}", e),
+        }
+    });
+    // Events start builds as queues free up; also run all queues every minute
+    // to start what is queued after synchronizing and anything an event missed
     let srv2 = srv.clone();
-    thread::spawn(move || { srv2.queue(); });
+    thread::spawn(move || { srv2.queue_loop(60); });
+    // Periodically reconcile against github in case webhooks were dropped
+    if let Some(interval) = srv.cfg().reconcile_interval {
+        let srv3 = srv.clone();
+        thread::spawn(move || { srv3.reconcile_loop(interval); });
+    }
     let addr = format!("0.0.0.0:{}", port);
     info!("Listening on {}", addr);
     Server::http(&addr.as_str()).unwrap().handle(srv).unwrap();
@@ -0,0 +1,138 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::fmt::Write;
+
+use super::{Pull, Progress};
+
+/// Webhook events handled by some forge; anything else is counted as "other"
+const KNOWN_EVENTS: &'static [&'static str] = &["issue_comment",
+                                                 "pull_request_comment",
+                                                 "pull_request",
+                                                 "pull_request_review",
+                                                 "push",
+                                                 "ping",
+                                                 "installation",
+                                                 "installation_repositories",
+                                                 "Merge Request Hook",
+                                                 "Note Hook",
+                                                 "Push Hook",
+                                                 "Pipeline Hook"];
+
+/// Counters exposed on the metrics route
+#[derive(Default)]
+pub struct Metrics {
+    /// Webhook events received by event type
+    events: Mutex<BTreeMap<String, usize>>,
+    /// Commands parsed from comments
+    commands: AtomicUsize,
+    /// Builds triggered from the queue
+    builds_triggered: AtomicUsize,
+    /// Build results accepted from CI
+    builds_finished: AtomicUsize,
+    /// PRs merged after a successful build
+    merges: AtomicUsize,
+    /// PRs moved to Failure after a failed build
+    failures: AtomicUsize,
+    /// Corrections made by reconciliation (missed webhooks)
+    corrections: AtomicUsize,
+    /// Pushes to auto branches by anyone but volf
+    foreign_auto_pushes: AtomicUsize,
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: usize) {
+    let _ = write!(out,
+                   "# HELP {name} {help}\n# TYPE {name} counter\n{name} {value}\n",
+                   name = name,
+                   help = help,
+                   value = value);
+}
+
+/// Escape a label value for the prometheus text format
+fn label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+impl Metrics {
+    /// Count a webhook event by its (unauthenticated) event header
+    ///
+    /// Only known event names get their own label, so senders can not grow the output.
+    pub fn event(&self, event: &str) {
+        let name = if KNOWN_EVENTS.iter().any(|e| *e == event) { event } else { "other" };
+        let mut events = self.events.lock().unwrap();
+        *events.entry(name.into()).or_insert(0) += 1;
+    }
+    pub fn commands(&self, n: usize) { self.commands.fetch_add(n, Ordering::Relaxed); }
+    pub fn build_triggered(&self) { self.builds_triggered.fetch_add(1, Ordering::Relaxed); }
+    pub fn build_finished(&self) { self.builds_finished.fetch_add(1, Ordering::Relaxed); }
+    pub fn merge(&self) { self.merges.fetch_add(1, Ordering::Relaxed); }
+    pub fn failure(&self) { self.failures.fetch_add(1, Ordering::Relaxed); }
+    pub fn corrections(&self, n: usize) { self.corrections.fetch_add(n, Ordering::Relaxed); }
+    pub fn foreign_auto_push(&self) { self.foreign_auto_pushes.fetch_add(1, Ordering::Relaxed); }
+
+    /// Render counters and queue gauges in the prometheus text format
+    pub fn render(&self, prs: &[Pull]) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP volf_webhook_events_total Webhook events received by type\n");
+        out.push_str("# TYPE volf_webhook_events_total counter\n");
+        for (event, n) in self.events.lock().unwrap().iter() {
+            let _ = write!(out,
+                           "volf_webhook_events_total{{event=\"{}\"}} {}\n",
+                           label(event),
+                           n);
+        }
+        counter(&mut out,
+                "volf_commands_total",
+                "Commands parsed from comments",
+                self.commands.load(Ordering::Relaxed));
+        counter(&mut out,
+                "volf_builds_triggered_total",
+                "Builds triggered from the queue",
+                self.builds_triggered.load(Ordering::Relaxed));
+        counter(&mut out,
+                "volf_builds_finished_total",
+                "Build results accepted from ci",
+                self.builds_finished.load(Ordering::Relaxed));
+        counter(&mut out,
+                "volf_merges_total",
+                "Pull requests merged",
+                self.merges.load(Ordering::Relaxed));
+        counter(&mut out,
+                "volf_failures_total",
+                "Pull requests failing their builds",
+                self.failures.load(Ordering::Relaxed));
+        counter(&mut out,
+                "volf_reconcile_corrections_total",
+                "Tracked pull requests corrected by reconciliation",
+                self.corrections.load(Ordering::Relaxed));
+        counter(&mut out,
+                "volf_foreign_auto_pushes_total",
+                "Pushes to auto branches by anyone but volf",
+                self.foreign_auto_pushes.load(Ordering::Relaxed));
+
+        let mut lengths = BTreeMap::new();
+        let mut states = BTreeMap::new();
+        for pr in prs {
+            *lengths.entry(&pr.repo).or_insert(0) += 1;
+            *states.entry(format!("{:?}", pr.state)).or_insert(0) += 1;
+        }
+        out.push_str("# HELP volf_queue_length Tracked pull requests per repository\n");
+        out.push_str("# TYPE volf_queue_length gauge\n");
+        for (repo, n) in lengths {
+            let _ = write!(out, "volf_queue_length{{repo=\"{}\"}} {}\n", label(repo), n);
+        }
+        out.push_str("# HELP volf_pulls Tracked pull requests per state\n");
+        out.push_str("# TYPE volf_pulls gauge\n");
+        for state in &[Progress::Failure,
+                       Progress::Ready,
+                       Progress::Pending,
+                       Progress::Testing,
+                       Progress::Success] {
+            let name = format!("{:?}", state);
+            let n = states.get(&name).cloned().unwrap_or(0);
+            let _ = write!(out, "volf_pulls{{state=\"{}\"}} {}\n", name, n);
+        }
+        out
+    }
+}
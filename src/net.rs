@@ -0,0 +1,50 @@
+use std::fs;
+use std::io::Read;
+
+use hyper::Client;
+use hyper::client::ProxyConfig;
+use hyper::net::{HttpConnector, HttpsConnector};
+use hyper_native_tls::NativeTlsClient;
+use native_tls::{Certificate, TlsConnector};
+use openssl::x509::X509;
+
+use super::{VolfError, VolfResult};
+use super::config::Connection;
+
+/// Tls client trusting the configured certificate authorities besides the system ones
+fn tls(conn: &Connection) -> VolfResult<NativeTlsClient> {
+    let mut builder = TlsConnector::builder()?;
+    if let Some(ref path) = conn.ca_bundle {
+        let mut pem = vec![];
+        fs::File::open(path)?.read_to_end(&mut pem)?;
+        for cert in X509::stack_from_pem(&pem)? {
+            builder.add_root_certificate(Certificate::from_der(&cert.to_der()?)?)?;
+        }
+    }
+    Ok(NativeTlsClient::from(builder.build()?))
+}
+
+/// Host and port of a proxy given as `host:port` or `http://host:port`
+fn proxy_address(proxy: &str) -> VolfResult<(String, u16)> {
+    let addr = proxy.trim_left_matches("http://").trim_right_matches('/');
+    let port = match addr.rfind(':') {
+        Some(i) => addr[i + 1..].parse().ok().map(|p| (&addr[..i], p)),
+        None => Some((addr, 80)),
+    };
+    match port {
+        Some((host, port)) if !host.is_empty() => Ok((host.into(), port)),
+        _ => Err(VolfError::Config(format!("invalid proxy {}", proxy))),
+    }
+}
+
+/// Client for outgoing http(s) connections honoring the proxy and certificate settings
+pub fn client(conn: &Connection) -> VolfResult<Client> {
+    let ssl = tls(conn)?;
+    match conn.proxy {
+        Some(ref proxy) => {
+            let (host, port) = proxy_address(proxy)?;
+            Ok(Client::with_proxy_config(ProxyConfig::new("http", host, port, HttpConnector, ssl)))
+        }
+        None => Ok(Client::with_connector(HttpsConnector::new(ssl))),
+    }
+}
@@ -0,0 +1,275 @@
+use std::collections::BTreeMap;
+use std::io::Read;
+
+use hyper::Client;
+use hyper::server::{Request, Response};
+use hyper::status::StatusCode;
+use hyper::header::{Accept, Authorization, ContentType, Cookie, Headers, Location, SetCookie,
+                    UserAgent, qitem};
+use hyper::mime::{Mime, TopLevel, SubLevel};
+use rand::{OsRng, Rng};
+use serde_json;
+use time;
+use url::form_urlencoded;
+
+use super::{VolfResult, VolfError};
+use super::server::ServerHandle;
+use super::config::GithubData;
+use super::auth;
+use super::net;
+
+/// Name of the cookie holding the session id
+const COOKIE: &'static str = "volf_session";
+
+/// Name of the form field carrying the session's csrf token
+pub const CSRF_FIELD: &'static str = "csrf";
+
+/// Seconds a login session stays valid
+const SESSION_LIFETIME: i64 = 8 * 3600;
+
+/// Seconds a user has to complete a login on github
+const LOGIN_TIMEOUT: i64 = 600;
+
+/// Most login attempts outstanding at once (GET /login is unauthenticated)
+const MAX_PENDING: usize = 1000;
+
+/// A logged in user
+#[derive(Clone)]
+pub struct Session {
+    /// Github login
+    pub login: String,
+    /// Token that forms posted by this session must echo
+    pub csrf: String,
+    /// Unix time the session expires at
+    expires: i64,
+}
+
+impl Session {
+    /// Whether a csrf token sent with a request matches this session's
+    pub fn csrf_ok(&self, given: Option<&str>) -> bool {
+        given.map_or(false, |t| auth::token_eq(&self.csrf, t))
+    }
+}
+
+/// Logged in users and outstanding login attempts
+#[derive(Default)]
+pub struct Sessions {
+    /// Session id -> logged in user
+    users: BTreeMap<String, Session>,
+    /// OAuth state parameter -> path to return to after login, and expiry
+    pending: BTreeMap<String, (String, i64)>,
+}
+
+impl Sessions {
+    /// Session owning a session id, unless expired
+    pub fn user(&self, id: &str) -> Option<Session> {
+        let now = time::get_time().sec;
+        self.users.get(id).and_then(|s| if s.expires > now { Some(s.clone()) } else { None })
+    }
+
+    /// Forget expired sessions and login attempts
+    fn expire(&mut self) {
+        let now = time::get_time().sec;
+        let users = self.users
+            .iter()
+            .filter(|&(_, s)| s.expires <= now)
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+        for id in users {
+            self.users.remove(&id);
+        }
+        let pending = self.pending
+            .iter()
+            .filter(|&(_, &(_, expires))| expires <= now)
+            .map(|(state, _)| state.clone())
+            .collect::<Vec<_>>();
+        for state in pending {
+            self.pending.remove(&state);
+        }
+    }
+}
+
+/// Random url safe token for session ids and oauth states
+fn random_token() -> VolfResult<String> {
+    let mut rng = OsRng::new()?;
+    Ok(rng.gen_ascii_chars().take(32).collect())
+}
+
+/// Parse an `application/x-www-form-urlencoded` string into key value pairs
+pub fn parse_form(data: &str) -> Vec<(String, String)> {
+    form_urlencoded::parse(data.as_bytes()).into_owned().collect()
+}
+
+#[derive(Deserialize)]
+struct AccessToken {
+    access_token: Option<String>,
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GithubUser {
+    login: String,
+}
+
+/// Exchange an oauth code for a user token, then look up who the token belongs to
+fn exchange(client: &Client, gh: &GithubData, code: &str) -> VolfResult<String> {
+    let body = form_urlencoded::Serializer::new(String::new())
+        .append_pair("client_id", &gh.app_client_id)
+        .append_pair("client_secret", &gh.app_client_secret)
+        .append_pair("code", code)
+        .finish();
+    let mut res = client.post(&format!("{}/login/oauth/access_token", gh.web_url))
+        .header(Accept(vec![qitem(Mime(TopLevel::Application, SubLevel::Json, vec![]))]))
+        .header(ContentType(Mime(TopLevel::Application, SubLevel::WwwFormUrlEncoded, vec![])))
+        .body(&body[..])
+        .send()?;
+    let mut data = String::new();
+    res.read_to_string(&mut data)?;
+    let token: AccessToken = serde_json::from_str(&data)?;
+    let token = match (token.access_token, token.error) {
+        (Some(t), _) => t,
+        (None, err) => return Err(VolfError::OAuth(err.unwrap_or_else(|| "no token".into()))),
+    };
+
+    let mut res = client.get(&format!("{}/user", gh.api_url))
+        .header(Authorization(format!("token {}", token)))
+        .header(UserAgent("volf".into()))
+        .send()?;
+    let mut data = String::new();
+    res.read_to_string(&mut data)?;
+    let user: GithubUser = serde_json::from_str(&data)?;
+    Ok(user.login)
+}
+
+/// OAuth routes and session lookups
+impl ServerHandle {
+    /// Session owning the session cookie in a request
+    pub fn session(&self, headers: &Headers) -> Option<Session> {
+        let cookies = match headers.get::<Cookie>() {
+            Some(&Cookie(ref cookies)) => cookies.clone(),
+            None => return None,
+        };
+        let sessions = self.sessions.lock().unwrap();
+        cookies.iter()
+            .flat_map(|c| c.split(';'))
+            .filter_map(|c| {
+                let mut kv = c.trim().splitn(2, '=');
+                match (kv.next(), kv.next()) {
+                    (Some(COOKIE), Some(id)) => sessions.user(id),
+                    _ => None,
+                }
+            })
+            .next()
+    }
+
+    /// GET /login?return_to=/queue/owner/repo
+    pub fn handle_login(&self, query: &str, mut res: Response) {
+        let return_to = parse_form(query)
+            .into_iter()
+            .find(|&(ref k, _)| k == "return_to")
+            .map(|(_, v)| v)
+            .and_then(|v| if v.starts_with('/') && !v.starts_with("//") { Some(v) } else { None })
+            .unwrap_or_else(|| "/api/queue".into());
+        let state = match random_token() {
+            Ok(s) => s,
+            Err(err) => {
+                warn!("Failed to create oauth state: {}", err);
+                *res.status_mut() = StatusCode::InternalServerError;
+                return;
+            }
+        };
+        {
+            let mut sessions = self.sessions.lock().unwrap();
+            sessions.expire();
+            if sessions.pending.len() >= MAX_PENDING {
+                warn!("Too many outstanding logins");
+                *res.status_mut() = StatusCode::ServiceUnavailable;
+                return;
+            }
+            let expires = time::get_time().sec + LOGIN_TIMEOUT;
+            sessions.pending.insert(state.clone(), (return_to, expires));
+        }
+        let cfg = self.cfg();
+        let url = format!("{}/login/oauth/authorize?client_id={}&state={}",
+                          cfg.github.web_url,
+                          cfg.github.app_client_id,
+                          state);
+        *res.status_mut() = StatusCode::Found;
+        res.headers_mut().set(Location(url));
+        res.send(b"").ok();
+    }
+
+    fn login(&self, query: &str) -> VolfResult<(String, String)> {
+        let params = parse_form(query);
+        let param = |name: &str| {
+            params.iter().find(|&&(ref k, _)| k == name).map(|&(_, ref v)| v.clone())
+        };
+        let (code, state) = match (param("code"), param("state")) {
+            (Some(c), Some(s)) => (c, s),
+            _ => return Err(VolfError::OAuth("missing code or state".into())),
+        };
+        let pending = {
+            let mut sessions = self.sessions.lock().unwrap();
+            sessions.expire();
+            sessions.pending.remove(&state)
+        };
+        let return_to = pending.map(|(r, _)| r)
+            .ok_or_else(|| VolfError::OAuth("unknown or expired state".into()))?;
+
+        let cfg = self.cfg();
+        let login = exchange(&net::client(&cfg.connection)?, &cfg.github, &code)?;
+        let id = random_token()?;
+        let session = Session {
+            login: login,
+            csrf: random_token()?,
+            expires: time::get_time().sec + SESSION_LIFETIME,
+        };
+        info!("{} logged in", session.login);
+        self.sessions.lock().unwrap().users.insert(id.clone(), session);
+        Ok((id, return_to))
+    }
+
+    /// GET /callback?code=..&state=..
+    pub fn handle_callback(&self, query: &str, mut res: Response) {
+        match self.login(query) {
+            Ok((id, return_to)) => {
+                *res.status_mut() = StatusCode::Found;
+                let cookie = format!("{}={}; Path=/; HttpOnly; Secure; SameSite=Lax; Max-Age={}",
+                                     COOKIE,
+                                     id,
+                                     SESSION_LIFETIME);
+                res.headers_mut().set(SetCookie(vec![cookie]));
+                res.headers_mut().set(Location(return_to));
+                res.send(b"").ok();
+            }
+            Err(err) => {
+                warn!("Login failed: {}", err);
+                *res.status_mut() = StatusCode::Forbidden;
+                res.send(b"login failed").ok();
+            }
+        }
+    }
+
+    /// Read the body of a request that requires a reviewer session for `repo`
+    ///
+    /// Forms posted with a session cookie must carry the session's csrf token.
+    /// Sets a Forbidden status and returns None when the user is not authorized.
+    pub fn reviewer_form(&self, repo: &str, req: &mut Request, res: &mut Response)
+                         -> Option<(String, Vec<(String, String)>)> {
+        let mut body = String::new();
+        if req.read_to_string(&mut body).is_err() {
+            *res.status_mut() = StatusCode::BadRequest;
+            return None;
+        }
+        let form = parse_form(&body);
+        let csrf = form.iter().find(|&&(ref k, _)| k == CSRF_FIELD).map(|&(_, ref v)| v.as_str());
+        match self.authorize(repo, &req.headers, csrf) {
+            Some(user) => Some((user, form)),
+            None => {
+                warn!("Rejecting queue action on {}", repo);
+                *res.status_mut() = StatusCode::Forbidden;
+                None
+            }
+        }
+    }
+}
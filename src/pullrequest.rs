@@ -1,8 +1,13 @@
 use std::cmp::Ordering;
+use std::mem;
+use std::thread;
+use std::time::Duration;
+use super::audit::{Action, Event, Source};
 use super::server::ServerHandle;
-use super::config::Repository;
+use super::VolfResult;
+use super::config::{Repository, Queue};
 
-#[derive(PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Progress {
     /// PR failed tests (to distinguish from Ready/Pending state)
     ///
@@ -33,24 +38,57 @@ impl Default for Progress {
     fn default() -> Progress { Progress::Ready }
 }
 
-#[derive(Default, PartialEq, Eq, PartialOrd)]
+/// Link to a build reported through the ci route
+#[derive(Serialize, PartialEq, Eq, PartialOrd)]
+pub struct BuildLink {
+    /// Name of the build (same as the required/optional build name)
+    pub name: String,
+    /// Url to the build log
+    pub url: String,
+    /// Whether the build passed
+    pub success: bool,
+}
+
+#[derive(Serialize, Default, PartialEq, Eq, PartialOrd)]
 pub struct Pull {
     /// The full owner/repo string
     pub repo: String,
     /// Title of PR
-    title: String,
+    pub title: String,
     /// The pull request number
     pub num: u64,
     /// The current state of the PR
-    state: Progress,
-    // TODO: need base and head sha for bookkeeping
-    // TODO: priority / blocked / rollup
+    pub state: Progress,
+    /// Branch the PR targets
+    pub base: String,
+    /// Changeset id of the PR head
+    pub head_sha: String,
+    /// Owner and branch of the PR head joined by a colon
+    pub head_label: String,
+    /// Whether this PR has been selected for a rollup
+    pub rollup: bool,
+    /// Priority in the queue (higher goes first)
+    pub priority: u32,
     /// Username of approver, if approved
-    approver: Option<String>,
+    pub approver: Option<String>,
     /// Whether this is allowed to progress to testing
     blocked: bool,
     /// Whether this PR is unmergeable
     unmergeable: bool,
+    /// Login of the PR author
+    pub author: String,
+    /// User granted approval rights on this PR only (until the head changes)
+    pub delegate: Option<String>,
+    /// Changeset of the merge commit currently being tested on auto
+    merge_sha: Option<String>,
+    /// Changeset of the base branch the merge commit under test was made on
+    #[serde(skip_serializing)]
+    base_sha: Option<String>,
+    /// Builds reported for the current merge commit
+    pub builds: Vec<BuildLink>,
+    /// Commands and transitions not yet moved to the audit log
+    #[serde(skip_serializing)]
+    events: Vec<Event>,
 }
 
 impl Ord for Pull {
@@ -65,6 +103,12 @@ impl Ord for Pull {
 
 // TODO: Cow
 impl Pull {
+    /// Order of testing within a queue: highest priority first, then lowest number
+    pub fn queue_order(&self, other: &Pull) -> Ordering {
+        other.priority.cmp(&self.priority).then(self.num.cmp(&other.num))
+    }
+
+
     pub fn new(full_name: &str, num: u64, title: &str) -> Pull {
         Pull {
             repo: full_name.into(),
@@ -73,66 +117,202 @@ impl Pull {
             ..Default::default()
         }
     }
+    /// Changeset the current state applies to (the merge commit while testing)
+    fn current_sha(&self) -> String {
+        self.merge_sha.clone().unwrap_or_else(|| self.head_sha.clone())
+    }
+    /// Change state and remember why for the audit log
+    fn transition(&mut self, to: Progress, reason: &str) {
+        if self.state != to {
+            let event = Event {
+                user: None,
+                sha: self.current_sha(),
+                action: Action::Transition {
+                    from: self.state,
+                    to: to,
+                    reason: reason.into(),
+                },
+            };
+            self.events.push(event);
+            self.state = to;
+        }
+    }
+    /// Remember a command issued on this PR for the audit log
+    pub fn record_command(&mut self, user: &str, cmd: &str) {
+        let event = Event {
+            user: Some(user.into()),
+            sha: self.current_sha(),
+            action: Action::Command(cmd.into()),
+        };
+        self.events.push(event);
+    }
+    /// Remember that volf stopped tracking this PR for the audit log
+    pub fn untrack(&mut self, reason: &str) {
+        let event = Event {
+            user: None,
+            sha: self.current_sha(),
+            action: Action::Untracked(reason.into()),
+        };
+        self.events.push(event);
+    }
+    /// Drain commands and transitions recorded since the last call
+    pub fn take_events(&mut self) -> Vec<Event> {
+        mem::replace(&mut self.events, vec![])
+    }
+    /// Move the PR to a new head, dropping any approval of the old one
+    pub fn set_head(&mut self, sha: &str) {
+        if self.head_sha != sha {
+            self.head_sha = sha.into();
+            self.approver = None;
+            self.delegate = None;
+            self.merge_sha = None;
+            self.transition(Progress::Ready, "new head");
+        }
+    }
+    /// Retarget the PR to another base branch, dropping any approval and delegation
+    pub fn set_base(&mut self, base: &str) {
+        if self.base != base {
+            self.base = base.into();
+            self.approver = None;
+            self.delegate = None;
+            self.merge_sha = None;
+            self.transition(Progress::Ready, "new base");
+        }
+    }
     pub fn approve(&mut self, approver: &str) -> bool {
         if self.blocked {
             false
         } else {
             self.approver = Some(approver.into());
-            self.state = Progress::Pending;
+            self.transition(Progress::Pending, "approved");
             true
         }
     }
 
 
     pub fn reset(&mut self) {
-        self.state = Progress::Ready;
+        self.transition(Progress::Ready, "reset");
         self.approver = None;
         self.blocked = false;
     }
-    pub fn failure(&mut self) { self.state = Progress::Failure; }
-    pub fn success(&mut self) {
-        self.state = Progress::Success;
-        // TODO: merge from here or in handle_build_result?
+    pub fn failure(&mut self) { self.transition(Progress::Failure, "build failed"); }
+    pub fn set_priority(&mut self, priority: u32) { self.priority = priority; }
+    pub fn set_title(&mut self, title: &str) { self.title = title.into(); }
+    pub fn set_head_label(&mut self, label: &str) { self.head_label = label.into(); }
+    pub fn set_author(&mut self, author: &str) { self.author = author.into(); }
+    pub fn set_delegate(&mut self, delegate: Option<&str>) {
+        self.delegate = delegate.map(|d| d.into());
+    }
+    /// Whether a user may approve or retry this PR (a reviewer or the delegate)
+    pub fn may_approve(&self, user: &str, reviewers: &[String]) -> bool {
+        reviewers.iter().any(|r| r == user) || self.delegate.as_ref().map_or(false, |d| d == user)
+    }
+    pub fn set_mergeable(&mut self, mergeable: bool) { self.unmergeable = !mergeable; }
+    pub fn set_rollup(&mut self, rollup: bool) { self.rollup = rollup; }
+    pub fn add_build(&mut self, name: &str, url: &str, success: bool) {
+        self.builds.retain(|b| b.name != name);
+        self.builds.push(BuildLink {
+            name: name.into(),
+            url: url.into(),
+            success: success,
+        });
+    }
+    /// Whether every build in `required` has passed on the current merge commit
+    pub fn builds_passed(&self, required: &[String]) -> bool {
+        required.iter().all(|r| self.builds.iter().any(|b| &b.name == r && b.success))
     }
+    /// Whether a build of `sha` is the one this PR is waiting for
+    pub fn is_testing(&self, sha: &str) -> bool {
+        self.state == Progress::Testing && self.merge_sha.as_ref().map_or(false, |s| s == sha)
+    }
+    pub fn success(&mut self) { self.transition(Progress::Success, "builds passed"); }
+    /// Base branch could not be moved to the tested merge commit
+    pub fn merge_failed(&mut self) { self.transition(Progress::Failure, "merge failed"); }
 
 
     pub fn unblock(&mut self) { self.blocked = false; }
-    pub fn block(&mut self) {
+    /// Block the PR from testing, returning false if it is already testing
+    pub fn block(&mut self) -> bool {
         match self.state {
             Progress::Testing => {
                 // too late - need to cancel builds to stop it
+                false
+            }
+            _ => {
+                self.blocked = true;
+                true
             }
-            _ => self.blocked = true,
         }
     }
 
     pub fn retry(&mut self) -> bool {
         if let Progress::Failure = self.state {
-            self.state = Progress::Pending;
+            self.transition(Progress::Pending, "retry");
             true
         } else {
             false
         }
     }
 
-    pub fn test(&mut self) {
-        self.state = Progress::Testing;
-        unimplemented!()
+    /// Start testing a merge commit of the current head into `base_sha` on the auto branch
+    pub fn test(&mut self, merge_sha: &str, base_sha: &str) {
+        self.merge_sha = Some(merge_sha.into());
+        self.base_sha = Some(base_sha.into());
+        self.transition(Progress::Testing, "queued");
+        self.builds.clear();
+    }
+    /// Changeset of the base branch the tested merge commit was made on
+    pub fn tested_base(&self) -> Option<&str> { self.base_sha.as_ref().map(|s| s.as_str()) }
+    /// Queue a tested PR again because its base moved before it could land
+    pub fn requeue(&mut self) {
+        self.transition(Progress::Pending, "base moved");
+        self.merge_sha = None;
+        self.base_sha = None;
+        self.builds.clear();
     }
 }
 // TODO: trait to Trigger builds?
-// TODO: how to handle build results?
 
 
-pub fn parse_commands(pr: &mut Pull, comment: String, user: String) {
+fn is_command(w: &str) -> bool {
+    w == "r+" || w == "retry" || w == "sync" || w.starts_with("p=") || w == "delegate+" ||
+    w == "delegate-" || delegate_name(w).map_or(false, |name| !name.is_empty())
+}
+
+/// User named by a `delegate=name` command, without a leading `@`
+fn delegate_name(w: &str) -> Option<&str> {
+    if w.starts_with("delegate=") {
+        Some(w["delegate=".len()..].trim_left_matches('@'))
+    } else {
+        None
+    }
+}
+
+/// Apply commands in a comment to a PR and return the number of commands found
+///
+/// `r+` and `retry` need a reviewer or the delegate of the PR,
+/// everything else (including delegation) needs a reviewer.
+pub fn parse_commands(pr: &mut Pull, comment: String, user: String, reviewers: &[String])
+                      -> usize {
     let cmds = comment
         .split_whitespace()
         .into_iter()
-        .filter(|&w| w == "r+" || w == "retry" || w == "sync")
+        .filter(|&w| is_command(w))
         .collect::<Vec<_>>();
+    let found = cmds.len();
+    let reviewer = reviewers.iter().any(|r| r == &user);
 
     for cmd in cmds {
         info!("{}#{} - {} cmd from {}", pr.repo, pr.num, cmd, user);
+        let allowed = match cmd {
+            "r+" | "retry" => pr.may_approve(&user, reviewers),
+            _ => reviewer,
+        };
+        if !allowed {
+            warn!("{}#{} - {} not allowed to {}", pr.repo, pr.num, user, cmd);
+            continue;
+        }
+        pr.record_command(&user, cmd);
         match cmd.as_ref() {
             "r+" => {
                 pr.approve(&user);
@@ -143,33 +323,149 @@ pub fn parse_commands(pr: &mut Pull, comment: String, user: String) {
             "reset" => {
                 pr.reset();
             }
+            "delegate+" => {
+                let author = pr.author.clone();
+                pr.set_delegate(Some(&author));
+            }
+            "delegate-" => {
+                pr.set_delegate(None);
+            }
+            d if d.starts_with("delegate=") => {
+                pr.set_delegate(delegate_name(d));
+            }
+            p if p.starts_with("p=") => {
+                if let Ok(priority) = p[2..].parse() {
+                    pr.set_priority(priority);
+                }
+            }
             _ => {}
         }
     }
+    found
+}
+
+/// Find a tree closing (`treeclosed=N`) or reopening (`treeopen`) command in a comment
+///
+/// Returns Some(Some(N)) when closing, Some(None) when reopening, and None otherwise.
+pub fn parse_tree_command(comment: &str) -> Option<Option<u32>> {
+    comment.split_whitespace()
+        .filter_map(|w| if w == "treeopen" {
+            Some(None)
+        } else if w.starts_with("treeclosed=") {
+            w["treeclosed=".len()..].parse().ok().map(Some)
+        } else {
+            None
+        })
+        .last()
 }
 
 /// periodic modifier thread of PullRequestState
 impl ServerHandle {
-    pub fn queue_repo(&self, repo: &Repository) {
-        // loop over Pull instances
-        let mut prs = self.prs.lock().unwrap();
-        for pr in prs.iter_mut() {
-            if pr.repo != repo.name {
-                continue;
+    /// Start testing the next PR in a queue, if nothing in it is testing already
+    pub fn queue_branch(&self, queue: &Queue) {
+        let _queueing = self.queueing.lock().unwrap();
+        let treeclosed = self.tree_closed(&queue.repo, &queue.base);
+        let next = {
+            let prs = self.prs.lock().unwrap();
+            let queued = prs.iter()
+                .filter(|pr| pr.repo == queue.repo && pr.base == queue.base)
+                .collect::<Vec<_>>();
+            if queued.iter().any(|pr| pr.state == Progress::Testing) {
+                return; // at most one thing testing at a time per queue
             }
-            if pr.state == Progress::Testing {
-                return; // at most one thing testing at a time
+            queued.into_iter()
+                .filter(|pr| {
+                    pr.state == Progress::Pending && !pr.unmergeable && pr.approver.is_some() &&
+                    !pr.blocked && treeclosed.map_or(true, |p| pr.priority >= p)
+                })
+                .min_by(|a, b| a.queue_order(b))
+                .map(|pr| (pr.num, pr.head_sha.clone()))
+        };
+        let (num, head) = match next {
+            Some(next) => next,
+            None => return,
+        };
+        debug!("{}#{} - testing on {}", queue.repo, num, queue.auto);
+        let started = match self.start_build(queue, num, &head) {
+            Ok(started) => started,
+            Err(e) => {
+                warn!("Failed to start testing {}#{}: {}", queue.repo, num, e);
+                return;
             }
-            if pr.state == Progress::Pending && !pr.unmergeable && pr.approver.is_some() &&
-               !pr.blocked {
-                pr.test();
+        };
+
+        let mut prs = self.prs.lock().unwrap();
+        // the PR may have moved on while we were talking to the forge
+        let pr = match prs.iter_mut().find(|pr| {
+            pr.repo == queue.repo && pr.num == num && pr.head_sha == head &&
+            pr.state == Progress::Pending
+        }) {
+            Some(pr) => pr,
+            None => return,
+        };
+        match started {
+            Some((merge, base)) => {
+                pr.test(&merge, &base);
+                self.metrics.build_triggered();
+            }
+            None => {
+                warn!("{}#{} - merge conflict with {}", queue.repo, num, queue.base);
+                pr.set_mergeable(false);
             }
         }
+        self.record_audit(pr, Source::Queue);
+    }
 
+    /// Reset the auto branch to the base branch and merge a PR head into it
+    ///
+    /// Returns the merge commit to test and the base changeset it was made on,
+    /// or None if the head conflicts with the base.
+    fn start_build(&self, queue: &Queue, num: u64, head: &str)
+                   -> VolfResult<Option<(String, String)>> {
+        let forge = self.forge(&queue.repo)?;
+        let base = forge.branch_head(&queue.repo, &queue.base)?;
+        forge.set_branch(&queue.repo, &queue.auto, &base, true)?;
+        let msg = format!("Auto merge of #{} into {}", num, queue.base);
+        let merge = forge.merge(&queue.repo, &queue.auto, head, &msg)?;
+        match merge {
+            Some(ref sha) => {
+                forge.set_status(&queue.repo, head, "volf", "pending", "Testing")?;
+                let msg = format!(":hourglass: Testing {} with merge {}", head, sha);
+                forge.comment(&queue.repo, num, &msg)?;
+            }
+            None => {
+                let msg = format!(":lock: Merge conflict with {}", queue.base);
+                forge.comment(&queue.repo, num, &msg)?;
+            }
+        }
+        Ok(merge.map(|merge| (merge, base)))
+    }
+    pub fn queue_repo(&self, repo: &Repository) {
+        for queue in repo.queues() {
+            self.queue_branch(&queue);
+        }
     }
     pub fn queue(&self) {
-        for repo in &self.cfg.repositories {
+        for repo in &self.repositories() {
             self.queue_repo(&repo);
         }
     }
+
+    /// Start the next builds of a repository after an event that may have freed or
+    /// filled one of its queues (an approval, a build result, a tree opening, ..)
+    ///
+    /// Must be called without holding the PR lock.
+    pub fn queue_for(&self, repo: &str) {
+        if let Some(r) = self.repository(repo) {
+            self.queue_repo(&r);
+        }
+    }
+
+    /// Run all queues forever at an interval, in case an event did not start a build
+    pub fn queue_loop(&self, interval: u64) {
+        loop {
+            self.queue();
+            thread::sleep(Duration::from_secs(interval));
+        }
+    }
 }
@@ -0,0 +1,125 @@
+use std::collections::BTreeMap;
+use std::thread;
+use std::time::Duration;
+
+use super::{Pull, VolfResult};
+use super::config::Repository;
+use super::audit::Source;
+use super::server::ServerHandle;
+
+/// Periodic correction of tracked PRs for webhooks github never delivered
+impl ServerHandle {
+    /// Diff the open PRs on github against the tracked PRs of a repository
+    ///
+    /// Adds missed PRs, drops closed or merged ones, and moves PRs to their current head.
+    /// Returns the number of corrections made.
+    pub fn reconcile_repo(&self, repo: &Repository) -> VolfResult<usize> {
+        let forge = self.forge(&repo.name)?;
+        // webhooks keep arriving during the fetch; only PRs unchanged since this
+        // snapshot are corrected, anything newer came from a webhook and wins
+        let tracked = self.prs
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|pr| pr.repo == repo.name)
+            .map(|pr| (pr.num, pr.head_sha.clone()))
+            .collect::<BTreeMap<_, _>>();
+        let open = forge.open_pulls(&repo.name)?
+            .into_iter()
+            .map(|p| (p.number, p))
+            .collect::<BTreeMap<_, _>>();
+
+        let mut corrections = 0;
+        let missing = {
+            let mut prs = self.prs.lock().unwrap();
+            let unchanged = |pr: &Pull| tracked.get(&pr.num) == Some(&pr.head_sha);
+            corrections += self.untrack(&mut prs, "closed", Source::Reconcile, |pr| {
+                let drop = pr.repo == repo.name && !open.contains_key(&pr.num) && unchanged(pr);
+                if drop {
+                    warn!("reconcile: dropping closed {}#{}", pr.repo, pr.num);
+                }
+                drop
+            });
+
+            for pr in prs.iter_mut().filter(|pr| pr.repo == repo.name && unchanged(pr)) {
+                let pull = match open.get(&pr.num) {
+                    Some(p) => p,
+                    None => continue,
+                };
+                let (title, head, base) = (&pull.title, &pull.head_sha, &pull.base);
+                if &pr.head_sha != head {
+                    warn!("reconcile: moving {}#{} from {} to {}",
+                          pr.repo,
+                          pr.num,
+                          pr.head_sha,
+                          head);
+                    pr.set_head(head);
+                    corrections += 1;
+                }
+                if &pr.title != title {
+                    warn!("reconcile: retitling {}#{}", pr.repo, pr.num);
+                    pr.set_title(title);
+                    corrections += 1;
+                }
+                if &pr.base != base {
+                    warn!("reconcile: retargeting {}#{} to {}", pr.repo, pr.num, base);
+                    pr.set_base(base);
+                    corrections += 1;
+                }
+                self.record_audit(pr, Source::Reconcile);
+            }
+
+            // PRs dropped during the fetch were closed by a webhook, so are not missed
+            open.keys()
+                .filter(|&num| !tracked.contains_key(num))
+                .filter(|&num| !prs.iter().any(|pr| pr.repo == repo.name && pr.num == *num))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+
+        // rebuilding missed PRs needs their history, so do that without the lock
+        for num in missing {
+            let mut pr = match repo.synchronize_pull(&*forge, &open[&num]) {
+                Ok(pr) => pr,
+                Err(e) => {
+                    warn!("reconcile: failed to rebuild {}#{}: {}", repo.name, num, e);
+                    continue;
+                }
+            };
+            // check again, a webhook may have added it meanwhile
+            let mut prs = self.prs.lock().unwrap();
+            if !prs.iter().any(|p| p.repo == repo.name && p.num == num) {
+                warn!("reconcile: adding missed {}#{}", repo.name, num);
+                self.record_audit(&mut pr, Source::Reconcile);
+                prs.push(pr);
+                corrections += 1;
+            }
+        }
+        Ok(corrections)
+    }
+
+    /// Reconcile all repositories once
+    ///
+    /// Repositories created since the last run that match a pattern are picked up too.
+    pub fn reconcile(&self) {
+        let _ = self.discover().map_err(|e| warn!("reconcile: failed to list repositories: {}", e));
+        for repo in &self.repositories() {
+            match self.reconcile_repo(repo) {
+                Ok(0) => debug!("reconcile: {} up to date", repo.name),
+                Ok(n) => {
+                    warn!("reconcile: {} corrections in {}", n, repo.name);
+                    self.metrics.corrections(n);
+                }
+                Err(e) => warn!("reconcile: failed for {}: {}", repo.name, e),
+            }
+        }
+    }
+
+    /// Reconcile all repositories forever at the configured interval
+    pub fn reconcile_loop(&self, interval: u64) {
+        loop {
+            thread::sleep(Duration::from_secs(interval));
+            self.reconcile();
+        }
+    }
+}
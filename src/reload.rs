@@ -0,0 +1,81 @@
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+use super::{VolfError, VolfResult, Progress};
+use super::audit::Source;
+use super::config::Config;
+use super::server::ServerHandle;
+
+/// The first setting two configs differ in that a reload can not change
+///
+/// Forge clients and their connections, the reconcile thread and the audit trail
+/// are set up from these at startup.
+fn fixed_setting(old: &Config, new: &Config) -> Option<&'static str> {
+    let app = |c: &Config| c.github.app.as_ref().map(|a| (a.app_id, a.private_key.clone()));
+    let gitlab = |c: &Config| {
+        c.gitlab.as_ref().map(|gl| (gl.url.clone(), gl.access_token.clone()))
+    };
+    let gitea = |c: &Config| c.gitea.as_ref().map(|gt| (gt.url.clone(), gt.access_token.clone()));
+    let connection = |c: &Config| (c.connection.ca_bundle.clone(), c.connection.proxy.clone());
+    let changes = [("port", old.port != new.port),
+                   ("github.api_url", old.github.api_url != new.github.api_url),
+                   ("github.access_token", old.github.access_token != new.github.access_token),
+                   ("github.app", app(old) != app(new)),
+                   ("gitlab", gitlab(old) != gitlab(new)),
+                   ("gitea", gitea(old) != gitea(new)),
+                   ("connection", connection(old) != connection(new)),
+                   ("reconcile_interval", old.reconcile_interval != new.reconcile_interval),
+                   ("audit_log", old.audit_log != new.audit_log)];
+    changes.iter().find(|&&(_, changed)| changed).map(|&(name, _)| name)
+}
+
+/// Configuration changes without restarting
+impl ServerHandle {
+    /// Re-read and validate the config file, then swap it in
+    ///
+    /// The running config is kept if the file does not parse or has problems.
+    pub fn reload(&self) -> VolfResult<()> {
+        let path = self.cfg_path
+            .clone()
+            .ok_or_else(|| VolfError::Config("no config file to reload from".into()))?;
+        let problems = Config::validate(&path)?;
+        if !problems.is_empty() {
+            let msgs = problems.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+            return Err(VolfError::Config(msgs.join(", ")));
+        }
+        self.swap_config(Config::read(&path)?)
+    }
+
+    /// Atomically replace the config, then track added and drop removed repositories
+    ///
+    /// PRs of repositories in both configs keep their state. PRs under test in a removed
+    /// repository are kept until their build finishes. Configs changing settings that
+    /// are only read at startup are rejected.
+    pub fn swap_config(&self, cfg: Config) -> VolfResult<()> {
+        if let Some(name) = fixed_setting(&self.cfg(), &cfg) {
+            return Err(VolfError::Config(format!("{} can not change without a restart", name)));
+        }
+        let before = self.repositories().into_iter().map(|r| r.name).collect::<BTreeSet<_>>();
+        *self.cfg.write().unwrap() = Arc::new(cfg);
+        self.refresh_repositories();
+        // patterns may have been added, so list the repositories they can match again
+        let _ = self.discover().map_err(|e| error!("Failed to list repositories: {}", e));
+        let after = self.repositories();
+
+        let dropped = {
+            let mut prs = self.prs.lock().unwrap();
+            self.untrack(&mut prs, "repository removed from the config", Source::Reload, |pr| {
+                pr.state != Progress::Testing && !after.iter().any(|r| r.name == pr.repo)
+            })
+        };
+        let added = after.iter().filter(|r| !before.contains(&r.name)).collect::<Vec<_>>();
+        info!("Reloaded config: {} repositories added, {} PRs of removed repositories dropped",
+              added.len(),
+              dropped);
+        for repo in added {
+            let _ = self.synchronize(repo)
+                .map_err(|e| error!("Failed to synchronize {}: {}", repo.name, e));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,48 @@
+use super::VolfResult;
+use super::config::{Repository, RepoFile, REPO_FILE};
+use super::server::ServerHandle;
+
+/// Commit status reporting whether `.volf.toml` is valid
+const STATUS_CONTEXT: &'static str = "volf/config";
+
+/// Settings kept in the repositories themselves
+impl ServerHandle {
+    /// Read `.volf.toml` from the base branch of a repository with `repo_config`
+    ///
+    /// An invalid file keeps the settings read before, and fails the `volf/config`
+    /// status on the base branch head so the team that broke it can see why.
+    pub fn load_repo_file(&self, repo: &Repository) -> VolfResult<()> {
+        if !repo.repo_config() {
+            return Ok(());
+        }
+        let forge = self.forge(&repo.name)?;
+        let base = repo.config_branch();
+        // read the file at the head the status goes on, even if the branch moves meanwhile
+        let head = forge.branch_head(&repo.name, &base)?;
+        let data = match forge.file(&repo.name, &head, REPO_FILE)? {
+            Some(data) => data,
+            None => {
+                debug!("No {} in {}:{} ({})", REPO_FILE, repo.name, base, head);
+                if self.repo_files.lock().unwrap().remove(&repo.name).is_some() {
+                    self.refresh_repositories();
+                }
+                return Ok(());
+            }
+        };
+        match RepoFile::parse(&data) {
+            Ok(file) => {
+                info!("Read {} of {}: {:?}", REPO_FILE, repo.name, file);
+                self.repo_files.lock().unwrap().insert(repo.name.clone(), file);
+                self.refresh_repositories();
+                forge.set_status(&repo.name, &head, STATUS_CONTEXT, "success", "Valid .volf.toml")
+            }
+            Err(e) => {
+                warn!("Invalid {} in {}:{}: {}", REPO_FILE, repo.name, base, e);
+                // github rejects status descriptions over 140 characters
+                let msg = format!("Invalid .volf.toml: {}", e);
+                let msg = msg.chars().take(140).collect::<String>();
+                forge.set_status(&repo.name, &head, STATUS_CONTEXT, "failure", &msg)
+            }
+        }
+    }
+}
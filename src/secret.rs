@@ -0,0 +1,140 @@
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::Read;
+use std::ops::Deref;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use super::{VolfError, VolfResult};
+
+/// A secret config value
+///
+/// Values can reference secrets kept outside `volf.json`:
+///
+/// - `${VAR}` is replaced by the environment variable `VAR` (anywhere in the value)
+/// - `file:/path` is replaced by the contents of a file (without trailing newlines)
+///
+/// References are resolved when the config is parsed, and serialized back unresolved.
+/// A reference that can not be resolved keeps the reason, so `Config::read` fails on
+/// it while validation can still check the rest of the config.
+/// The resolved value is never printed by `Debug`.
+#[derive(Clone, Default, PartialEq)]
+pub struct Secret {
+    /// Value as written in the config
+    reference: String,
+    /// Value with all references resolved
+    value: String,
+    /// Why the references could not be resolved
+    error: Option<String>,
+}
+
+impl Secret {
+    /// Resolve the references in a config value
+    pub fn resolve(reference: &str) -> VolfResult<Secret> {
+        let secret = Secret::parse(reference);
+        match secret.error {
+            Some(e) => Err(VolfError::Config(e)),
+            None => Ok(secret),
+        }
+    }
+
+    /// Resolve the references in a config value, keeping the reason if that fails
+    fn parse(reference: &str) -> Secret {
+        let value = if reference.starts_with("file:") {
+            read_file(&reference["file:".len()..])
+        } else {
+            interpolate(reference)
+        };
+        let (value, error) = match value {
+            Ok(value) => (value, None),
+            Err(e) => (String::new(), Some(e)),
+        };
+        Secret {
+            reference: reference.into(),
+            value: value,
+            error: error,
+        }
+    }
+
+    /// Whether the secret has no value
+    pub fn is_empty(&self) -> bool { self.value.is_empty() }
+
+    /// The value as written in the config
+    pub fn reference(&self) -> &str { &self.reference }
+
+    /// Why the references could not be resolved, if they could not
+    pub fn error(&self) -> Option<&str> { self.error.as_ref().map(|e| e.as_str()) }
+
+    /// Check the references for syntax errors and unset variables
+    ///
+    /// `file:` references are only readable where volf runs, so they are not checked.
+    pub fn check(&self) -> Result<(), String> {
+        if self.reference.starts_with("file:") {
+            return Ok(());
+        }
+        interpolate(&self.reference).map(|_| ())
+    }
+}
+
+/// Contents of a secret file without trailing newlines
+fn read_file(path: &str) -> Result<String, String> {
+    let mut data = String::new();
+    fs::File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut data))
+        .map_err(|e| format!("secret file {}: {}", path, e))?;
+    Ok(data.trim_right_matches(|c| c == '\n' || c == '\r').into())
+}
+
+/// Replace every `${VAR}` in a value by the environment variable `VAR`
+fn interpolate(reference: &str) -> Result<String, String> {
+    let mut value = String::new();
+    let mut rest = reference;
+    while let Some(start) = rest.find("${") {
+        let end = match rest[start..].find('}') {
+            Some(i) => start + i,
+            None => return Err("unterminated ${ in a secret".into()),
+        };
+        let var = &rest[start + 2..end];
+        let resolved = env::var(var)
+            .map_err(|_| format!("environment variable {} for a secret is not set", var))?;
+        value.push_str(&rest[..start]);
+        value.push_str(&resolved);
+        rest = &rest[end + 1..];
+    }
+    value.push_str(rest);
+    Ok(value)
+}
+
+/// A literal secret without references
+impl<'a> From<&'a str> for Secret {
+    fn from(value: &'a str) -> Secret {
+        Secret {
+            reference: value.into(),
+            value: value.into(),
+            error: None,
+        }
+    }
+}
+
+impl Deref for Secret {
+    type Target = str;
+    fn deref(&self) -> &str { &self.value }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "Secret(<redacted>)") }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.reference)
+    }
+}
+
+impl Deserialize for Secret {
+    fn deserialize<D: Deserializer>(deserializer: D) -> Result<Secret, D::Error> {
+        let reference = String::deserialize(deserializer)?;
+        Ok(Secret::parse(&reference))
+    }
+}
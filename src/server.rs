@@ -1,35 +1,294 @@
 use hyper::server::{Request, Response, Handler};
 use hyper::status::StatusCode;
 use hyper::method::Method;
-use std::sync::{Arc, Mutex};
+use hyper::header::ContentType;
+use std::sync::{Arc, Mutex, RwLock};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::io::Read;
+use std::path::PathBuf;
+use std::time::Duration;
 
 use super::Pull;
-use super::config::Config;
-use super::VolfResult;
+use super::config::{Config, Repository, RepoFile, ForgeKind, REPO_FILE, is_pattern};
+use super::{VolfResult, VolfError};
+use super::auth;
+use super::metrics::Metrics;
+use super::oauth::Sessions;
+use super::forge::{Forge, FakeForge};
+use super::github_app::AppAuth;
+use super::eventlog::Deliveries;
+use super::audit::{Audit, Source};
 
 use serde_json;
-use hubcaps::Github;
 
 /// Convenience alias for main application state
 pub type PullRequestState = Arc<Mutex<Vec<Pull>>>;
 
+/// Closed trees: priority threshold keyed by (owner/repo, base branch)
+pub type TreeState = Arc<Mutex<BTreeMap<(String, String), u32>>>;
+
 #[derive(Clone)]
 pub struct ServerHandle {
     /// Shared state
     pub prs: PullRequestState,
-    /// Shared github client instance
-    pub gh: Arc<Github>,
-    /// Shared Volf configuration data
-    pub cfg: Arc<Config>,
+    /// Shared tree closure state per queue
+    pub trees: TreeState,
+    /// Shared clients of the services hosting the repositories
+    pub forges: BTreeMap<ForgeKind, Arc<Forge>>,
+    /// Repositories visible on each forge that `repositories` patterns refer to
+    pub visible: Arc<Mutex<BTreeMap<ForgeKind, Vec<String>>>>,
+    /// Settings read from `.volf.toml` of repositories with `repo_config`
+    pub repo_files: Arc<Mutex<BTreeMap<String, RepoFile>>>,
+    /// Default branches of tracked repositories, looked up once per repository
+    pub default_branches: Arc<Mutex<BTreeMap<String, String>>>,
+    /// Every tracked repository, rebuilt by `refresh_repositories` when its sources change
+    pub tracked: Arc<Mutex<Vec<Repository>>>,
+    /// Github app whose installations add repositories (if running as an app)
+    pub app: Option<Arc<AppAuth>>,
+    /// Shared Volf configuration data, swapped as a whole on reloads
+    pub cfg: Arc<RwLock<Arc<Config>>>,
+    /// File the configuration is reloaded from
+    pub cfg_path: Option<PathBuf>,
+    /// Whether initial synchronization has completed
+    pub ready: Arc<AtomicBool>,
+    /// Counters for the metrics route
+    pub metrics: Arc<Metrics>,
+    /// OAuth sessions of users logged in to the queue page
+    pub sessions: Arc<Mutex<Sessions>>,
+    /// Recently handled webhook deliveries
+    pub deliveries: Arc<Mutex<Deliveries>>,
+    /// Trail of every command and state transition
+    pub audit: Arc<Mutex<Audit>>,
+    /// Held while a queue picks and starts its next build, so only one event starts it
+    pub queueing: Arc<Mutex<()>>,
+    /// Wait after a base branch push before rechecking mergeability (none rechecks inline)
+    pub recheck_delay: Duration,
 }
 impl ServerHandle {
-    pub fn new(prs: PullRequestState, gh: Arc<Github>, cfg: Arc<Config>) -> ServerHandle {
-        ServerHandle {
+    /// Create a server with `forge` serving github repositories
+    pub fn new(prs: PullRequestState, forge: Arc<Forge>, cfg: Arc<Config>) -> ServerHandle {
+        let mut forges = BTreeMap::new();
+        forges.insert(ForgeKind::Github, forge);
+        let srv = ServerHandle {
             prs: prs,
-            gh: gh,
-            cfg: cfg,
+            trees: Arc::new(Mutex::new(BTreeMap::new())),
+            forges: forges,
+            visible: Arc::new(Mutex::new(BTreeMap::new())),
+            repo_files: Arc::new(Mutex::new(BTreeMap::new())),
+            default_branches: Arc::new(Mutex::new(BTreeMap::new())),
+            tracked: Arc::new(Mutex::new(vec![])),
+            app: None,
+            audit: Arc::new(Mutex::new(Audit::new(cfg.audit_log.clone()))),
+            deliveries: Arc::new(Mutex::new(Deliveries::from_log(cfg.event_log.as_ref()))),
+            cfg: Arc::new(RwLock::new(cfg)),
+            cfg_path: None,
+            ready: Arc::new(AtomicBool::new(false)),
+            metrics: Arc::new(Metrics::default()),
+            sessions: Arc::new(Mutex::new(Sessions::default())),
+            queueing: Arc::new(Mutex::new(())),
+            // forges compute mergeability lazily in the background after a push
+            recheck_delay: Duration::from_secs(5),
+        };
+        srv.refresh_repositories();
+        srv
+    }
+
+    /// A server that handles events offline, for replaying an event log
+    ///
+    /// Every kind of forge is a `FakeForge`, and neither events nor the audit trail
+    /// are written to the logs of `cfg`.
+    pub fn for_replay(cfg: &Config) -> ServerHandle {
+        let mut cfg = cfg.clone();
+        cfg.event_log = None;
+        cfg.audit_log = None;
+        let prs: PullRequestState = Arc::new(Mutex::new(vec![]));
+        let mut srv = ServerHandle::new(prs, Arc::new(FakeForge::default()), Arc::new(cfg));
+        srv.add_forge(ForgeKind::Gitlab, Arc::new(FakeForge::default()));
+        srv.add_forge(ForgeKind::Gitea, Arc::new(FakeForge::default()));
+        srv
+    }
+
+    /// Serve repositories on another kind of forge
+    pub fn add_forge(&mut self, kind: ForgeKind, forge: Arc<Forge>) {
+        self.forges.insert(kind, forge);
+    }
+
+    /// Track repositories the github app is installed on
+    pub fn set_app(&mut self, app: Arc<AppAuth>) {
+        self.app = Some(app);
+        self.refresh_repositories();
+    }
+
+    /// Change how long to wait after base pushes before rechecking mergeability
+    pub fn set_recheck_delay(&mut self, delay: Duration) { self.recheck_delay = delay; }
+
+    /// Allow reloading the configuration from a file
+    pub fn set_config_path(&mut self, path: PathBuf) { self.cfg_path = Some(path); }
+
+    /// The current configuration
+    ///
+    /// Hold on to the returned config for the duration of a request, so it sees a
+    /// consistent config even if it is reloaded meanwhile.
+    pub fn cfg(&self) -> Arc<Config> { self.cfg.read().unwrap().clone() }
+
+    /// Every tracked repository: the configured ones, then app installations
+    pub fn repositories(&self) -> Vec<Repository> { self.tracked.lock().unwrap().clone() }
+
+    /// Expand the config into the tracked repositories again
+    ///
+    /// Needed after the config, the visible repositories, app installations, `.volf.toml`
+    /// files or default branches change. Settings from `.volf.toml` files are layered
+    /// over the configured settings.
+    pub fn refresh_repositories(&self) {
+        let cfg = self.cfg();
+        let mut visible = self.visible.lock().unwrap().clone();
+        let installed = self.app.as_ref().map(|auth| auth.repositories()).unwrap_or_default();
+        visible.entry(ForgeKind::Github).or_insert_with(Vec::new).extend(installed.clone());
+        let mut repos = cfg.expand(&visible);
+        if let Some(app) = cfg.github.app.as_ref() {
+            for name in installed {
+                if !repos.iter().any(|r| r.name == name) {
+                    repos.push(cfg.layer(app.defaults.repository(&name)));
+                }
+            }
         }
+        {
+            let files = self.repo_files.lock().unwrap();
+            for repo in repos.iter_mut().filter(|r| r.repo_config()) {
+                if let Some(file) = files.get(&repo.name) {
+                    file.apply(repo);
+                }
+            }
+            let default_branches = self.default_branches.lock().unwrap();
+            for repo in &mut repos {
+                repo.default_branch = default_branches.get(&repo.name).cloned();
+            }
+        }
+        *self.tracked.lock().unwrap() = repos;
+    }
+
+    /// Look up the default branch of a repository unless it is already known
+    ///
+    /// Repositories without `branches` queue PRs into their default branch.
+    pub fn learn_default_branch(&self, repo: &Repository) -> VolfResult<()> {
+        if self.look_up_default_branch(repo)? {
+            self.refresh_repositories();
+        }
+        Ok(())
+    }
+
+    /// Store the default branch of a repository, returning whether it was new
+    fn look_up_default_branch(&self, repo: &Repository) -> VolfResult<bool> {
+        if repo.default_branch.is_some() ||
+           self.default_branches.lock().unwrap().contains_key(&repo.name) {
+            return Ok(false);
+        }
+        let branch = self.forge(&repo.name)?.default_branch(&repo.name)?;
+        debug!("{} defaults to {}", repo.name, branch);
+        self.default_branches.lock().unwrap().insert(repo.name.clone(), branch);
+        Ok(true)
+    }
+
+    /// List the repositories visible on forges that `repositories` patterns refer to
+    ///
+    /// Github app installations are tracked by the app instead. Default branches of
+    /// newly tracked repositories are looked up too.
+    pub fn discover(&self) -> VolfResult<()> {
+        let cfg = self.cfg();
+        let mut kinds = cfg.repositories
+            .iter()
+            .filter(|r| is_pattern(&r.name))
+            .map(|r| r.forge)
+            .collect::<BTreeSet<_>>();
+        if self.app.is_some() {
+            kinds.remove(&ForgeKind::Github);
+        }
+        let mut visible = BTreeMap::new();
+        for kind in kinds {
+            let forge = self.forges
+                .get(&kind)
+                .ok_or_else(|| VolfError::Forge(format!("no {:?} forge", kind)))?;
+            let names = forge.repositories()?;
+            info!("{} repositories visible on {:?}", names.len(), kind);
+            visible.insert(kind, names);
+        }
+        *self.visible.lock().unwrap() = visible;
+        self.refresh_repositories();
+        let mut learned = false;
+        for repo in &self.repositories() {
+            match self.look_up_default_branch(repo) {
+                Ok(new) => learned = learned || new,
+                Err(e) => warn!("Failed to look up the default branch of {}: {}", repo.name, e),
+            }
+        }
+        if learned {
+            self.refresh_repositories();
+        }
+        Ok(())
+    }
+
+    /// Find a tracked repository by owner/name
+    pub fn repository(&self, name: &str) -> Option<Repository> {
+        self.tracked.lock().unwrap().iter().find(|r| r.name == name).cloned()
+    }
+
+    /// Client of the forge hosting a tracked repository
+    pub fn forge(&self, repo: &str) -> VolfResult<Arc<Forge>> {
+        let kind = self.repository(repo).map(|r| r.forge).unwrap_or_default();
+        self.forges
+            .get(&kind)
+            .cloned()
+            .ok_or_else(|| VolfError::Forge(format!("no {:?} forge for {}", kind, repo)))
+    }
+
+    /// Priority threshold below which a queue is closed, if closed
+    pub fn tree_closed(&self, repo: &str, base: &str) -> Option<u32> {
+        let trees = self.trees.lock().unwrap();
+        trees.get(&(repo.to_string(), base.to_string())).cloned()
+    }
+
+    /// Mark the server as ready to receive traffic
+    pub fn set_ready(&self) { self.ready.store(true, Ordering::SeqCst); }
+
+    /// Re-synchronize a repository from github and replace its tracked PRs
+    ///
+    /// The new state is built without holding the lock, then swapped in one go.
+    pub fn synchronize(&self, repo: &Repository) -> VolfResult<()> {
+        let _ = self.learn_default_branch(repo)
+            .map_err(|e| warn!("Failed to look up the default branch of {}: {}", repo.name, e));
+        let repo = &self.repository(&repo.name).unwrap_or_else(|| repo.clone());
+        let _ = self.load_repo_file(repo)
+            .map_err(|e| warn!("Failed to read {} of {}: {}", REPO_FILE, repo.name, e));
+        let repo = &self.repository(&repo.name).unwrap_or_else(|| repo.clone());
+        let (mut pulls, trees) = repo.synchronize(&*self.forge(&repo.name)?)?;
+        for pr in &mut pulls {
+            self.record_audit(pr, Source::Sync);
+        }
+        let mut prs = self.prs.lock().unwrap();
+        let open = pulls.iter().map(|pr| pr.num).collect::<Vec<_>>();
+        self.untrack(&mut prs,
+                     "closed",
+                     Source::Sync,
+                     |pr| pr.repo == repo.name && !open.contains(&pr.num));
+        prs.retain(|pr| pr.repo != repo.name);
+        info!("Synchronized {} open PRs in {}", pulls.len(), repo.name);
+        prs.extend(pulls);
+        // bases without a tree command on an open PR keep their current state
+        let mut closed = self.trees.lock().unwrap();
+        for (base, tree) in trees {
+            let key = (repo.name.clone(), base);
+            match tree {
+                Some(p) => {
+                    info!("{}:{} - treeclosed={} from comments", key.0, key.1, p);
+                    closed.insert(key, p);
+                }
+                None => {
+                    closed.remove(&key);
+                }
+            }
+        }
+        Ok(())
     }
 }
 
@@ -37,16 +296,76 @@ impl ServerHandle {
 impl Handler for ServerHandle {
     fn handle(&self, req: Request, mut res: Response) {
         let uri = format!("{}", req.uri);
+        let (path, query) = match uri.find('?') {
+            Some(i) => (&uri[..i], &uri[i + 1..]),
+            None => (&uri[..], ""),
+        };
         if uri == "/github" && req.method == Method::Post {
             self.handle_webhook(req, res)
+        } else if uri == "/gitlab" && req.method == Method::Post {
+            self.handle_gitlab_webhook(req, res)
+        } else if uri == "/gitea" && req.method == Method::Post {
+            self.handle_gitea_webhook(req, res)
         } else if uri == "/ci" && req.method == Method::Post {
             self.handle_ci(req, res)
+        } else if path == "/login" && req.method == Method::Get {
+            self.handle_login(query, res)
+        } else if path == "/callback" && req.method == Method::Get {
+            self.handle_callback(query, res)
+        } else if path.starts_with("/api/pr/") && path.ends_with("/history") &&
+                  req.method == Method::Get {
+            self.handle_history(&path["/api/pr/".len()..], res)
+        } else if path == "/api/audit" && req.method == Method::Get {
+            self.handle_audit_export(&req.headers, res)
+        } else if path.starts_with("/api/pr/") && req.method == Method::Post {
+            self.handle_pr_action(&path["/api/pr/".len()..], query, req, res)
+        } else if path == "/api/config/reload" && req.method == Method::Post {
+            self.handle_config_reload(req, res)
+        } else if path.starts_with("/api/repo/") && req.method == Method::Post {
+            self.handle_repo_action(&path["/api/repo/".len()..], req, res)
+        } else if path.starts_with("/queue/") && req.method == Method::Post {
+            self.handle_queue_action(&path["/queue/".len()..], req, res)
+        } else if path.starts_with("/queue/") && req.method == Method::Get {
+            let session = self.session(&req.headers);
+            self.handle_queue_page(&path["/queue/".len()..], session, res)
+        } else if uri == "/api/trees" && req.method == Method::Get {
+            self.handle_trees_api(res)
+        } else if uri == "/api/queue" && req.method == Method::Get {
+            self.handle_queue_api(None, res)
+        } else if uri.starts_with("/api/queue/") && req.method == Method::Get {
+            self.handle_queue_api(Some(&uri["/api/queue/".len()..]), res)
+        } else if uri == "/healthz" && req.method == Method::Get {
+            res.send(b"ok").ok();
+        } else if uri == "/readyz" && req.method == Method::Get {
+            self.handle_readiness(res)
+        } else if uri == "/metrics" && req.method == Method::Get {
+            self.handle_metrics(res)
         } else {
             *res.status_mut() = StatusCode::MethodNotAllowed
         };
     }
 }
 
+/// Probes for load balancers and monitoring
+impl ServerHandle {
+    fn handle_readiness(&self, mut res: Response) {
+        if self.ready.load(Ordering::SeqCst) {
+            res.send(b"ok").ok();
+        } else {
+            *res.status_mut() = StatusCode::ServiceUnavailable;
+            res.send(b"synchronizing").ok();
+        }
+    }
+
+    fn handle_metrics(&self, mut res: Response) {
+        let body = {
+            let prs = self.prs.lock().unwrap();
+            self.metrics.render(&prs)
+        };
+        res.headers_mut().set(ContentType::plaintext());
+        res.send(body.as_bytes()).ok();
+    }
+}
 
 // -----------------------------------------------------------------------------
 
@@ -60,44 +379,196 @@ pub struct BuildResult {
     pub repo: String,
     /// PR number: TODO: require? could just iterate through..
     pub number: u64,
-    /// Changeset id of build (for sanity)
+    /// Changeset id of build (must match the merge commit under test)
     pub sha: String,
     /// Whether the build succeeded
     pub success: bool,
+    /// Name of the build (one of the required or optional builds)
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Link to the build for the queue page
+    #[serde(default)]
+    pub url: Option<String>,
 }
 
 
+/// name of the CI backend posting a build result
+header! {(XVolfCi, "X-Volf-Ci") => [String]}
+
+/// signature of the build result, HMAC-SHA1 of the payload using the backend secret
+header! {(XVolfSignature, "X-Volf-Signature") => [String]}
+
 /// Extra routes for CI
 impl ServerHandle {
-    fn handle_build_result(&self, payload: &str) -> VolfResult<()> {
+    fn verify_ci(&self, ci: &str, payload: &str, signature: &str) -> VolfResult<()> {
+        match self.cfg().ci_backend(ci) {
+            Some(backend) if auth::verify(&backend.secret, payload, signature) => Ok(()),
+            _ => Err(VolfError::InvalidSignature(ci.into())),
+        }
+    }
+
+    /// Apply a build result POST'd by CI
+    pub fn handle_build_result(&self, payload: &str) -> VolfResult<()> {
         // 1. deserialize payload into BuildResult
         let res: BuildResult = serde_json::from_str(&payload)?;
+        self.build_result(res)
+    }
+
+    /// Apply a build result, landing or failing the PR when it is decisive
+    pub fn build_result(&self, res: BuildResult) -> VolfResult<()> {
         // 2. match up build name to a PR
-        let mut prs = self.prs.lock().unwrap();
-        if let Some(pr) = prs.iter_mut().find(|ref pr| {
-            pr.num == res.number && pr.repo == res.repo
-        }) {
+        let outcome = {
+            let mut prs = self.prs.lock().unwrap();
+            // PRs of repositories removed from the config only stay until their build finishes
+            if self.repository(&res.repo).is_none() {
+                info!("dropping {}#{} of an untracked repository", res.repo, res.number);
+                self.untrack(&mut prs,
+                             "repository no longer tracked",
+                             Source::Ci,
+                             |pr| pr.num == res.number && pr.repo == res.repo);
+                return Ok(());
+            }
+            let pr = match prs.iter_mut()
+                .find(|ref pr| pr.num == res.number && pr.repo == res.repo) {
+                Some(pr) => pr,
+                None => {
+                    warn!("ignoring build result on untracked pr {}", res.number);
+                    return Ok(());
+                }
+            };
             debug!("found corresponding pr {}", pr.num);
-            // TODO: call status API to set required status on head_sha
-            if res.success {
-                //  - check status API to see what's left
-                //  - if nothing left:
-                //      - call pr.success() (merges and closes)
+            if !pr.is_testing(&res.sha) {
+                warn!("discarding stale build result for {}#{} at {}",
+                      res.repo,
+                      res.number,
+                      res.sha);
+                return Ok(());
+            }
+            if let Some(ref name) = res.name {
+                pr.add_build(name, res.url.as_ref().map_or("", |u| u), res.success);
+            }
+            self.metrics.build_finished();
+            let done = if res.success {
+                // each base branch has its own required builds
+                let queue = self.repository(&pr.repo).and_then(|r| r.queue(&pr.base));
+                if queue.map_or(false, |q| pr.builds_passed(&q.required_builds)) {
+                    pr.success();
+                    Some(true)
+                } else {
+                    None // waiting for more builds
+                }
             } else {
                 pr.failure(); // move queue to next pr
+                self.metrics.failure();
+                Some(false)
+            };
+            self.record_audit(pr, Source::Ci);
+            let tested_base = pr.tested_base().unwrap_or("").to_string();
+            done.map(|success| (success, pr.base.clone(), tested_base, pr.head_sha.clone()))
+        };
+        // 3. talk to the forge without holding the lock
+        let reported = match outcome {
+            Some((true, base, tested_base, head)) => {
+                self.land(&res.repo, res.number, &base, &tested_base, &head, &res.sha)
+            }
+            Some((false, _, _, head)) => {
+                self.forge(&res.repo).and_then(|forge| {
+                    forge.set_status(&res.repo, &head, "volf", "failure", "Build failed")?;
+                    let msg = format!(":broken_heart: Test failed on merge {}", res.sha);
+                    forge.comment(&res.repo, res.number, &msg)
+                })
+            }
+            None => return Ok(()),
+        };
+        // 4. the queue is free again
+        self.queue_for(&res.repo);
+        reported
+    }
+
+    /// Fast-forward the base branch to a tested merge commit and stop tracking the PR
+    ///
+    /// If the base moved away from `tested_base` since testing started, the merge commit
+    /// is not what would land, so the PR is queued again instead.
+    fn land(&self, repo: &str, num: u64, base: &str, tested_base: &str, head: &str,
+            merge: &str)
+            -> VolfResult<()> {
+        let forge = self.forge(repo)?;
+        let strategy = self.repository(repo).map(|r| r.merge_strategy()).unwrap_or_default();
+        let landed = match forge.branch_head(repo, base) {
+            Ok(ref sha) if sha != tested_base => {
+                return self.requeue(repo, num, base, head);
+            }
+            Ok(_) => forge.land(repo, num, base, head, merge, strategy),
+            Err(e) => Err(e),
+        };
+        {
+            let mut prs = self.prs.lock().unwrap();
+            let idx = prs.iter().position(|pr| pr.num == num && pr.repo == repo);
+            match (idx, landed.is_ok()) {
+                (Some(i), true) => {
+                    let mut pr = prs.remove(i);
+                    pr.untrack("merged");
+                    self.record_audit(&mut pr, Source::Ci);
+                }
+                (Some(i), false) => {
+                    prs[i].merge_failed();
+                    self.record_audit(&mut prs[i], Source::Ci);
+                }
+                (None, _) => {}
             }
-        } else {
-            warn!("ignoring comment on untracked pr {}", res.number);
         }
-        Ok(())
+        if let Err(e) = landed {
+            warn!("Failed to merge {}#{} into {}: {}", repo, num, base, e);
+            forge.set_status(repo, head, "volf", "error", "Merge failed")?;
+            return Err(e);
+        }
+        info!("{}#{} merged into {} as {}", repo, num, base, merge);
+        self.metrics.merge();
+        forge.set_status(repo, head, "volf", "success", "Merged")?;
+        let msg = format!(":sunny: Test successful - merged {} into {}", merge, base);
+        forge.comment(repo, num, &msg)
+    }
+
+    /// Queue a PR that passed its builds again because its base moved meanwhile
+    fn requeue(&self, repo: &str, num: u64, base: &str, head: &str) -> VolfResult<()> {
+        info!("{}#{} - {} moved since testing started, queueing again", repo, num, base);
+        {
+            let mut prs = self.prs.lock().unwrap();
+            if let Some(pr) = prs.iter_mut().find(|pr| pr.num == num && pr.repo == repo) {
+                pr.requeue();
+                self.record_audit(pr, Source::Ci);
+            }
+        }
+        let forge = self.forge(repo)?;
+        forge.set_status(repo, head, "volf", "pending", "Base moved, queued again")?;
+        let msg = format!(":arrows_counterclockwise: {} moved since testing started - \
+                           testing again",
+                          base);
+        forge.comment(repo, num, &msg)
     }
 
-    pub fn handle_ci(&self, mut req: Request, res: Response) {
+    pub fn handle_ci(&self, mut req: Request, mut res: Response) {
         let mut payload = String::new();
-        if let Ok(_) = req.read_to_string(&mut payload) {
-            debug!("ci result: {}", payload);
+        let headers = req.headers.clone();
+        if let (Some(&XVolfCi(ref ci)), Some(&XVolfSignature(ref signature))) =
+            (headers.get::<XVolfCi>(), headers.get::<XVolfSignature>()) {
+            if let Err(err) = req.read_to_string(&mut payload) {
+                warn!("Failed to read ci res from {}: {}", ci, err);
+                *res.status_mut() = StatusCode::BadRequest;
+                return;
+            }
+            if let Err(err) = self.verify_ci(ci, &payload, signature) {
+                warn!("Rejecting ci res: {}", err);
+                *res.status_mut() = StatusCode::Unauthorized;
+                return;
+            }
+            debug!("ci result from {}: {}", ci, payload);
             let _ = self.handle_build_result(&payload)
                 .map_err(|err| warn!("Failed to handle ci res {}", err));
+        } else {
+            warn!("Rejecting unsigned ci res");
+            *res.status_mut() = StatusCode::Unauthorized;
+            return;
         }
         res.send(b"ok").ok();
     }
@@ -0,0 +1,355 @@
+//! Semantic checks and JSON schema of `volf.json`
+//!
+//! Serde only rejects configs that do not parse. The checks here catch configs that
+//! parse but can not work, and anchor each problem to a line of the config source.
+
+use std::fmt;
+use std::collections::BTreeSet;
+
+use serde_json::Value;
+
+use super::config::{Config, ForgeKind, MergeStrategy};
+use super::secret::Secret;
+
+/// A problem found in the config
+#[derive(Debug)]
+pub struct Problem {
+    /// Line of the config source the problem is on (1-based)
+    pub line: usize,
+    /// What is wrong
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// Finds the lines config values are on
+///
+/// Lines are compared by their keys and values, so this works for json, toml and yaml.
+struct Source<'a> {
+    lines: Vec<Vec<&'a str>>,
+}
+
+/// Keys and values of a line or config value
+fn tokens(s: &str) -> Vec<&str> {
+    let plain = |c: char| c.is_alphanumeric() || "_-/.".contains(c);
+    s.split(|c| !plain(c)).filter(|t| !t.is_empty()).collect()
+}
+
+impl<'a> Source<'a> {
+    fn new(source: &'a str) -> Source<'a> {
+        Source {
+            lines: source.lines()
+                .map(|l| {
+                    let mut tokens = tokens(l);
+                    // dotted toml keys like [github.app]
+                    let keys = tokens.iter().flat_map(|t| t.split('.')).collect::<Vec<_>>();
+                    tokens.extend(keys);
+                    tokens
+                })
+                .collect(),
+        }
+    }
+
+    /// First line at or after `from` with all of `tokens` as keys or values
+    ///
+    /// Falls back to `from` so problems stay near their section when the source
+    /// is formatted unexpectedly.
+    fn find(&self, from: usize, tokens: &[&str]) -> usize {
+        self.lines
+            .iter()
+            .enumerate()
+            .skip(from.saturating_sub(1))
+            .find(|&(_, l)| tokens.iter().all(|t| l.contains(t)))
+            .map(|(i, _)| i + 1)
+            .unwrap_or(from)
+    }
+}
+
+/// Whether `name` is `owner/repo` (gitlab also allows nested `group/subgroup/repo`)
+fn valid_name(name: &str, forge: ForgeKind) -> bool {
+    let parts = name.split('/').collect::<Vec<_>>();
+    let nested = forge == ForgeKind::Gitlab && parts.len() > 2;
+    (parts.len() == 2 || nested) &&
+    parts.iter().all(|p| !p.is_empty() && !p.contains(char::is_whitespace))
+}
+
+/// Check a parsed config for values that can not work
+pub fn validate(cfg: &Config, source: &str) -> Vec<Problem> {
+    let src = Source::new(source);
+    let mut problems = vec![];
+    {
+        let mut problem = |line: usize, message: String| {
+            problems.push(Problem {
+                line: line,
+                message: message,
+            })
+        };
+        // unresolvable secrets are reported below rather than as empty
+        let empty = |s: &Secret| s.is_empty() && s.error().is_none();
+
+        for (name, secret) in cfg.secrets() {
+            if let Err(e) = secret.check() {
+                let line = src.find(1, &tokens(secret.reference()));
+                problem(line, format!("{}: {}", name, e));
+            }
+        }
+
+        if cfg.port == 0 || cfg.port > 65535 {
+            problem(src.find(1, &["port"]), format!("invalid port {}", cfg.port));
+        }
+
+        if !cfg.github.app_client_id.is_empty() && empty(&cfg.github.app_client_secret) {
+            let line = src.find(1, &["app_client_secret"]);
+            problem(line, "github.app_client_secret is empty".into());
+        }
+        if let Some(ref app) = cfg.github.app {
+            if empty(&app.webhook_secret) {
+                let line = src.find(src.find(1, &["app"]), &["webhook_secret"]);
+                problem(line, "github.app.webhook_secret is empty".into());
+            }
+        }
+        if let Some(ref gl) = cfg.gitlab {
+            let start = src.find(1, &["gitlab"]);
+            if empty(&gl.access_token) {
+                let line = src.find(start, &["access_token"]);
+                problem(line, "gitlab.access_token is empty".into());
+            }
+            if empty(&gl.webhook_token) {
+                let line = src.find(start, &["webhook_token"]);
+                problem(line, "gitlab.webhook_token is empty".into());
+            }
+        }
+        if let Some(ref gt) = cfg.gitea {
+            if empty(&gt.access_token) {
+                let line = src.find(src.find(1, &["gitea"]), &["access_token"]);
+                problem(line, "gitea.access_token is empty".into());
+            }
+        }
+        if let Some(ref token) = cfg.admin_token {
+            if empty(token) {
+                problem(src.find(1, &["admin_token"]), "admin_token is empty".into());
+            }
+        }
+
+        let mut seen = BTreeSet::new();
+        let mut from = src.find(1, &["repositories"]);
+        for repo in &cfg.repositories {
+            let line = src.find(from, &["name", &repo.name]);
+            from = line + 1;
+            if !valid_name(&repo.name, repo.forge) {
+                problem(line, format!("repository {} is not named owner/repo", repo.name));
+            }
+            if !seen.insert(&repo.name) {
+                problem(line, format!("repository {} is listed twice", repo.name));
+            }
+            let configured = match repo.forge {
+                ForgeKind::Github => true,
+                ForgeKind::Gitlab => cfg.gitlab.is_some(),
+                ForgeKind::Gitea => cfg.gitea.is_some(),
+            };
+            if !configured {
+                problem(line,
+                        format!("repository {} uses forge {:?} without its settings",
+                                repo.name,
+                                repo.forge));
+            }
+            // settings left unset are inherited from patterns and defaults
+            let layered = cfg.layer(repo.clone());
+            // gitlab webhooks are authenticated with gitlab.webhook_token instead
+            let no_secret = layered.github_secret.as_ref().map_or(true, |s| empty(s));
+            if repo.forge != ForgeKind::Gitlab && no_secret {
+                problem(src.find(line, &["github_secret"]),
+                        format!("repository {} has an empty github_secret", repo.name));
+            }
+            let rebase = layered.merge_strategy() == MergeStrategy::Rebase;
+            if repo.forge == ForgeKind::Gitlab && rebase {
+                problem(src.find(line, &["merge_strategy"]),
+                        format!("repository {} can not land by rebasing on gitlab", repo.name));
+            }
+            let optional = src.find(line, &["optional_builds"]);
+            let required = layered.required_builds();
+            for build in required.iter().filter(|b| layered.optional_builds().contains(b)) {
+                problem(src.find(optional, &[build.as_str()]),
+                        format!("build {} of {} is both required and optional",
+                                build,
+                                repo.name));
+            }
+        }
+
+        let mut from = src.find(1, &["ci"]);
+        for ci in &cfg.ci {
+            let line = src.find(from, &["name", &ci.name]);
+            from = line + 1;
+            if empty(&ci.secret) {
+                problem(src.find(line, &["secret"]),
+                        format!("ci backend {} has an empty secret", ci.name));
+            }
+        }
+    }
+    problems.sort_by_key(|p| p.line);
+    problems
+}
+
+fn string(description: &str) -> Value { json!({"type": "string", "description": description}) }
+
+fn strings(description: &str) -> Value {
+    json!({"type": "array", "items": {"type": "string"}, "description": description})
+}
+
+fn secret(description: &str) -> Value {
+    string(&format!("{} (`${{VAR}}` or `file:/path` references are resolved)", description))
+}
+
+fn labels() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "rollup": string("Label marking a PR for rollups"),
+            "block": string("Label blocking a PR from testing")
+        }
+    })
+}
+
+fn merge_strategy() -> Value {
+    json!({
+        "enum": ["merge", "squash", "rebase"],
+        "default": "merge",
+        "description": "How tested PRs land (gitlab can not rebase)"
+    })
+}
+
+fn branches() -> Value {
+    json!({
+        "type": "array",
+        "description": "Base branches with independent queues (the default branch only if empty)",
+        "items": {
+            "type": "object",
+            "required": ["name"],
+            "properties": {
+                "name": string("Name of the base branch"),
+                "auto": string("Branch to test merges on"),
+                "required_builds": strings("Required status builds for this branch")
+            }
+        }
+    })
+}
+
+/// JSON schema (draft 4) of `volf.json` for editor completion
+pub fn schema() -> Value {
+    let repository = json!({
+        "type": "object",
+        "required": ["name"],
+        "properties": {
+            "name": {
+                "type": "string",
+                "pattern": "^[^/\\s]+(/[^/\\s]+)+$",
+                "description": "Repository owner + name, or a pattern like myorg/*"
+            },
+            "required_builds": strings("Required status builds"),
+            "optional_builds": strings("Optional status builds"),
+            "github_secret": secret("Webhook secret (github and gitea)"),
+            "reviewers": strings("Users allowed to approve and manage the queue"),
+            "labels": labels(),
+            "branches": branches(),
+            "merge_strategy": merge_strategy(),
+            "forge": {"enum": ["github", "gitlab", "gitea"], "default": "github"},
+            "repo_config": {
+                "type": "boolean",
+                "description": "Layer .volf.toml from the first base branch over these settings"
+            }
+        }
+    });
+    let defaults = json!({
+        "type": "object",
+        "description": "Settings of repositories wherever their entries leave them empty",
+        "properties": {
+            "required_builds": strings("Required status builds"),
+            "optional_builds": strings("Optional status builds"),
+            "github_secret": secret("Webhook secret"),
+            "reviewers": strings("Users allowed to approve and manage the queue"),
+            "labels": labels(),
+            "branches": branches(),
+            "merge_strategy": merge_strategy(),
+            "repo_config": {"type": "boolean"}
+        }
+    });
+    let forge_login = string("Username of the volf user");
+    json!({
+        "$schema": "http://json-schema.org/draft-04/schema#",
+        "title": "volf.json",
+        "type": "object",
+        "required": ["port", "github", "repositories"],
+        "properties": {
+            "port": {"type": "integer", "minimum": 1, "maximum": 65535},
+            "github": {
+                "type": "object",
+                "required": ["app_client_id", "app_client_secret"],
+                "properties": {
+                    "api_url": string("Api root (https://HOST/api/v3 for github enterprise)"),
+                    "web_url": string("Web root (https://HOST for github enterprise)"),
+                    "access_token": secret("Personal access token (GITHUB_TOKEN if empty)"),
+                    "app": {
+                        "type": "object",
+                        "required": ["app_id", "private_key", "webhook_secret"],
+                        "properties": {
+                            "app_id": {"type": "integer"},
+                            "private_key": string("Path to the PEM private key of the app"),
+                            "webhook_secret": secret("Webhook secret of the app"),
+                            "defaults": defaults.clone()
+                        }
+                    },
+                    "app_client_id": string("Client id for volf app"),
+                    "app_client_secret": secret("Client secret for volf app"),
+                    "login": string("Login of the volf machine account")
+                }
+            },
+            "gitlab": {
+                "type": "object",
+                "required": ["url", "access_token", "webhook_token"],
+                "properties": {
+                    "url": string("Root url of the instance"),
+                    "access_token": secret("Personal access token of the volf user"),
+                    "webhook_token": secret("Secret token configured on webhooks"),
+                    "login": forge_login.clone()
+                }
+            },
+            "gitea": {
+                "type": "object",
+                "required": ["url", "access_token"],
+                "properties": {
+                    "url": string("Root url of the instance"),
+                    "access_token": secret("Access token of the volf user"),
+                    "login": forge_login
+                }
+            },
+            "connection": {
+                "type": "object",
+                "properties": {
+                    "ca_bundle": string("PEM file of extra certificate authorities to trust"),
+                    "proxy": string("HTTP proxy as host:port")
+                }
+            },
+            "defaults": defaults,
+            "repositories": {"type": "array", "items": repository},
+            "admin_token": secret("Token for the admin http api"),
+            "reconcile_interval": {"type": "integer", "minimum": 1},
+            "event_log": string("Path of an append-only log of raw webhook events"),
+            "audit_log": string("Path of an append-only JSON lines audit log"),
+            "ci": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "required": ["name", "secret"],
+                    "properties": {
+                        "name": string("Name of the backend (sent in the X-Volf-Ci header)"),
+                        "secret": secret("Shared secret used to sign build results")
+                    }
+                }
+            }
+        }
+    })
+}
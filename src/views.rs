@@ -0,0 +1,213 @@
+use hyper::server::{Request, Response};
+use hyper::status::StatusCode;
+use hyper::header::{ContentType, Location};
+use serde_json;
+
+use super::{Pull, VolfResult};
+use super::server::ServerHandle;
+use super::audit::Source;
+use super::oauth::{Session, CSRF_FIELD};
+
+/// Minimal escaping of user controlled text (titles, usernames) in html
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn render_row(pr: &Pull, link: &Fn(u64) -> String) -> String {
+    let builds = pr.builds
+        .iter()
+        .map(|b| format!("<a href=\"{}\">{}</a>", escape(&b.url), escape(&b.name)))
+        .collect::<Vec<_>>()
+        .join(" ");
+    format!("<tr><td><a href=\"{link}\">{num}</a></td>\
+             <td>{base}</td><td>{state:?}{rollup}</td><td>{priority}</td><td>{approver}</td>\
+             <td>{title}</td><td>{builds}</td></tr>",
+            link = escape(&link(pr.num)),
+            rollup = if pr.rollup { " (rollup)" } else { "" },
+            num = pr.num,
+            state = pr.state,
+            base = escape(&pr.base),
+            priority = pr.priority,
+            approver = escape(pr.approver.as_ref().map_or("", |s| s)),
+            title = escape(&pr.title),
+            builds = builds)
+}
+
+/// Queue actions available to logged in users, or a login link
+fn render_actions(repo: &str, session: Option<&Session>) -> String {
+    match session {
+        Some(s) => {
+            let csrf = format!("<input type=\"hidden\" name=\"{}\" value=\"{}\">",
+                               CSRF_FIELD,
+                               escape(&s.csrf));
+            format!("<p>Logged in as {user}</p>\n\
+                     <form method=\"post\" action=\"/queue/{repo}/priority\">{csrf}\
+                     #<input type=\"text\" name=\"num\" size=\"5\"> \
+                     p=<input type=\"text\" name=\"priority\" size=\"3\"> \
+                     <input type=\"submit\" value=\"Set priority\"></form>\n",
+                    user = escape(&s.login),
+                    repo = escape(repo),
+                    csrf = csrf)
+        }
+        None => {
+            format!("<p><a href=\"/login?return_to=/queue/{}\">Log in</a></p>\n",
+                    escape(repo))
+        }
+    }
+}
+
+/// Sort PRs by state, then in the order they are tested in
+fn sort_queue(queue: &mut [&Pull]) { queue.sort_by(|a, b| b.cmp(a).then(a.queue_order(b))); }
+
+/// Render the queue of a single repository as an html table
+///
+/// `trees` lists the closed base branches with their priority thresholds,
+/// and PR numbers link to the pages `link` returns for them.
+pub fn render_queue(repo: &str,
+                    prs: &[&Pull],
+                    trees: &[(String, u32)],
+                    link: &Fn(u64) -> String,
+                    session: Option<&Session>)
+                    -> String {
+    let rows = prs.iter().map(|pr| render_row(pr, link)).collect::<Vec<_>>().join("\n");
+    let closed = trees.iter()
+        .map(|&(ref base, p)| {
+            format!("<p><strong>Tree closed</strong> on {} for priority below {}</p>\n",
+                    escape(base),
+                    p)
+        })
+        .collect::<String>();
+    format!("<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\">\
+             <title>volf - {repo}</title></head>\n<body>\n<h1>{repo}</h1>\n\
+             <p>{len} pull requests</p>\n{closed}{actions}<table>\n\
+             <tr><th>#</th><th>Base</th><th>State</th><th>Priority</th><th>Approver</th>\
+             <th>Title</th><th>Builds</th></tr>\n{rows}\n</table>\n</body>\n</html>\n",
+            repo = escape(repo),
+            len = prs.len(),
+            closed = closed,
+            actions = render_actions(repo, session),
+            rows = rows)
+}
+
+/// Views of the queue and actions from the queue page
+impl ServerHandle {
+    /// Snapshot of the queue for `repo` (all repos if None) in queue order
+    pub fn queue_json(&self, repo: Option<&str>) -> VolfResult<String> {
+        let prs = self.prs.lock().unwrap();
+        let mut queue = prs.iter()
+            .filter(|pr| repo.map_or(true, |r| pr.repo == r))
+            .collect::<Vec<_>>();
+        sort_queue(&mut queue);
+        Ok(serde_json::to_string(&queue)?)
+    }
+
+    /// Closed trees of all queues with their priority thresholds
+    pub fn trees_json(&self) -> VolfResult<String> {
+        let trees = self.trees
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&(ref repo, ref base), p)| json!({"repo": repo, "base": base, "closed": p}))
+            .collect::<Vec<_>>();
+        Ok(serde_json::to_string(&trees)?)
+    }
+
+    /// GET /queue/<owner>/<repo>
+    pub fn handle_queue_page(&self, repo: &str, session: Option<Session>, mut res: Response) {
+        let forge = match self.repository(repo) {
+            Some(r) => r.forge,
+            None => {
+                *res.status_mut() = StatusCode::NotFound;
+                return;
+            }
+        };
+        let cfg = self.cfg();
+        let link = |num| cfg.pull_url(forge, repo, num);
+        let trees = self.trees
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|&(&(ref r, _), _)| r == repo)
+            .map(|(&(_, ref base), p)| (base.clone(), *p))
+            .collect::<Vec<_>>();
+        let html = {
+            let prs = self.prs.lock().unwrap();
+            let mut queue = prs.iter().filter(|pr| pr.repo == repo).collect::<Vec<_>>();
+            sort_queue(&mut queue);
+            render_queue(repo, &queue, &trees, &link, session.as_ref())
+        };
+        res.headers_mut().set(ContentType::html());
+        res.send(html.as_bytes()).ok();
+    }
+
+    /// GET /api/queue and GET /api/queue/<owner>/<repo>
+    pub fn handle_queue_api(&self, repo: Option<&str>, mut res: Response) {
+        match self.queue_json(repo) {
+            Ok(json) => {
+                res.headers_mut().set(ContentType::json());
+                res.send(json.as_bytes()).ok();
+            }
+            Err(err) => {
+                warn!("Failed to serialize queue: {}", err);
+                *res.status_mut() = StatusCode::InternalServerError;
+            }
+        }
+    }
+
+    /// GET /api/trees
+    pub fn handle_trees_api(&self, mut res: Response) {
+        match self.trees_json() {
+            Ok(json) => {
+                res.headers_mut().set(ContentType::json());
+                res.send(json.as_bytes()).ok();
+            }
+            Err(err) => {
+                warn!("Failed to serialize trees: {}", err);
+                *res.status_mut() = StatusCode::InternalServerError;
+            }
+        }
+    }
+
+    /// POST /queue/<owner>/<repo>/<action> from the queue page forms
+    pub fn handle_queue_action(&self, path: &str, mut req: Request, mut res: Response) {
+        let (repo, action) = match path.rfind('/') {
+            Some(i) => (&path[..i], &path[i + 1..]),
+            None => {
+                *res.status_mut() = StatusCode::NotFound;
+                return;
+            }
+        };
+        let (user, form) = match self.reviewer_form(repo, &mut req, &mut res) {
+            Some(x) => x,
+            None => return,
+        };
+        let nums = form.iter()
+            .filter(|&&(ref k, _)| k == "num")
+            .filter_map(|&(_, ref v)| v.trim().parse::<u64>().ok())
+            .collect::<Vec<_>>();
+        let priority = form.iter()
+            .find(|&&(ref k, _)| k == "priority")
+            .and_then(|&(_, ref v)| v.trim().parse::<u32>().ok());
+
+        {
+            let mut prs = self.prs.lock().unwrap();
+            for pr in prs.iter_mut().filter(|pr| pr.repo == repo && nums.contains(&pr.num)) {
+                if let ("priority", Some(p)) = (action, priority) {
+                    info!("{}#{} - p={} from {}", pr.repo, pr.num, p, user);
+                    pr.set_priority(p);
+                    pr.record_command(&user, &format!("p={}", p));
+                }
+                self.record_audit(pr, Source::Http);
+            }
+        }
+        // a higher priority may pass a closed tree
+        self.queue_for(repo);
+        *res.status_mut() = StatusCode::SeeOther;
+        res.headers_mut().set(Location(format!("/queue/{}", repo)));
+        res.send(b"").ok();
+    }
+}
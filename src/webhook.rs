@@ -1,8 +1,16 @@
 use serde_json;
 use hyper::server::{Request, Response};
+use hyper::status::StatusCode;
 use std::io::Read;
-use super::{Pull, VolfResult, VolfError, parse_commands};
+use std::thread;
+use std::time::Duration;
+use super::{Pull, Progress, VolfResult, VolfError, parse_commands};
+use super::auth;
+use super::pullrequest::parse_tree_command;
+use super::audit::Source;
+use super::config::{ForgeKind, REPO_FILE};
 use super::server::ServerHandle;
+use super::forge::{PullInfo, MERGE_PREFIX};
 
 // -----------------------------------------------------------------------------
 // Minor structs parts of various event types
@@ -18,6 +26,12 @@ pub struct Repository {
     pub full_name: String,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct InstallationId {
+    /// Unique id of a github app installation
+    pub id: u64,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct Comment {
     /// User creating the comment
@@ -28,8 +42,9 @@ pub struct Comment {
 
 #[derive(Deserialize, Debug)]
 pub struct PullRequestIssue {
-    /// Unique PR number typically refernced by #n
-    pub number: u64,
+    /// Api url of the PR (the PR number is on the Issue, gitea omits this)
+    #[serde(default)]
+    pub url: String,
 }
 
 #[derive(Deserialize, Debug)]
@@ -42,14 +57,24 @@ pub struct Issue {
     pub pull_request: Option<PullRequestIssue>,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct Label {
+    /// Name of the label
+    pub name: String,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct PullRequestRef {
-    /// Ref name (only works with serde atm due to reserved keyword..)
-    // _ref: String,
+    /// Branch name
+    #[serde(rename = "ref")]
+    pub git_ref: String,
+    /// Owner and branch name joined by a colon
+    pub label: String,
     /// Changeset id
     pub sha: String,
-    /// Owning user
-    pub user: User,
+    /// Owning user (not sent by gitea)
+    #[serde(default)]
+    pub user: Option<User>,
     /// Respository containing the ref
     pub repo: Repository,
 }
@@ -66,6 +91,31 @@ pub struct PullRequestInner {
     pub head: PullRequestRef,
     /// State of destination (master typically)
     pub base: PullRequestRef,
+    /// Body of PR (not sent as a normal Comment struct)
+    pub body: Option<String>,
+    /// Current labels (used by gitea label updates)
+    #[serde(default)]
+    pub labels: Vec<Label>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Change {
+    /// Previous value
+    pub from: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct BaseChange {
+    /// Previous base changeset
+    pub sha: Change,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Changes {
+    /// Set if the title was edited
+    pub title: Option<Change>,
+    /// Set if the PR was retargeted to another base branch
+    pub base: Option<BaseChange>,
 }
 
 // -----------------------------------------------------------------------------
@@ -74,7 +124,7 @@ pub struct PullRequestInner {
 /// Subset of github events that we need
 #[derive(Deserialize, Debug)]
 pub struct PullRequest {
-    /// Action taken (opened/reopened/closed/assigned/unassigned)
+    /// Action taken (opened/reopened/closed/edited/synchronize/labeled/unlabeled/..)
     pub action: String,
     /// Unique PR number typically refernced by #n
     pub number: u64,
@@ -84,17 +134,46 @@ pub struct PullRequest {
     pub repository: Repository,
     /// Poster of PR
     pub sender: User,
-    /// Body of PR (not sent as a normal Comment struct)
-    pub body: String,
+    /// What changed on edited actions
+    pub changes: Option<Changes>,
+    /// Label added or removed on labeled/unlabeled actions
+    pub label: Option<Label>,
 }
 
-// TODO: look into review events now; want to trigger on "all accepted" rather than r+
+#[derive(Deserialize, Debug)]
+pub struct ReviewedPull {
+    /// Unique PR number typically refernced by #n
+    pub number: u64,
+}
 
+#[derive(Deserialize, Debug)]
+pub struct Review {
+    /// Reviewer
+    pub user: User,
+    /// Review summary (may contain commands)
+    pub body: Option<String>,
+}
+
+/// A review submitted on a PR; its summary is read for commands like a comment
+#[derive(Deserialize, Debug)]
+pub struct PullRequestReview {
+    /// Action taken (submitted/edited/dismissed)
+    pub action: String,
+    /// The review
+    pub review: Review,
+    /// The reviewed PR
+    pub pull_request: ReviewedPull,
+    /// Repository of the PR
+    pub repository: Repository,
+}
+
+// TODO: trigger on "all accepted" reviews rather than r+
 
 #[derive(Deserialize, Debug)]
 pub struct Push {
-    /// Ref name  (only works with serde atm due to reserved keyword..)
-    // _ref: String,
+    /// Full ref name (refs/heads/branch for branches)
+    #[serde(rename = "ref")]
+    pub git_ref: String,
     /// Changeset id of last change pushed
     pub after: String,
     /// The sha before the push
@@ -103,6 +182,30 @@ pub struct Push {
     pub repository: Repository,
     /// User sending the change
     pub sender: User, // we only use login anyway
+    /// Commits pushed (with the files they changed)
+    #[serde(default)]
+    pub commits: Vec<PushCommit>,
+}
+
+/// Files changed by a pushed commit (same shape on github, gitlab and gitea)
+#[derive(Deserialize, Debug)]
+pub struct PushCommit {
+    /// Paths of added files
+    #[serde(default)]
+    pub added: Vec<String>,
+    /// Paths of modified files
+    #[serde(default)]
+    pub modified: Vec<String>,
+    /// Paths of removed files
+    #[serde(default)]
+    pub removed: Vec<String>,
+}
+
+impl PushCommit {
+    /// Whether the commit changed a file
+    pub fn touches(&self, path: &str) -> bool {
+        self.added.iter().chain(&self.modified).chain(&self.removed).any(|f| f == path)
+    }
 }
 #[derive(Deserialize, Debug)]
 pub struct IssueComment {
@@ -116,6 +219,34 @@ pub struct IssueComment {
     pub repository: Repository,
     /// Sender of the comment
     pub sender: User,
+    /// Whether the issue is a PR (gitea only)
+    #[serde(default)]
+    pub is_pull: bool,
+}
+/// Github app installation (created/deleted)
+#[derive(Deserialize, Debug)]
+pub struct Installation {
+    /// Action taken (created/deleted/..)
+    pub action: String,
+    /// The installation
+    pub installation: InstallationId,
+    /// Repositories of a new installation
+    #[serde(default)]
+    pub repositories: Vec<Repository>,
+}
+/// Repositories added to or removed from a github app installation
+#[derive(Deserialize, Debug)]
+pub struct InstallationRepositories {
+    /// Action taken (added/removed)
+    pub action: String,
+    /// The installation
+    pub installation: InstallationId,
+    /// Repositories added on added actions
+    #[serde(default)]
+    pub repositories_added: Vec<Repository>,
+    /// Repositories removed on removed actions
+    #[serde(default)]
+    pub repositories_removed: Vec<Repository>,
 }
 #[derive(Deserialize, Debug)]
 pub struct Ping {
@@ -127,26 +258,306 @@ pub struct Ping {
 // -----------------------------------------------------------------------------
 // event handlers
 
+/// Forge independent reactions to events on PRs and branches
+impl ServerHandle {
+    /// Start tracking a newly opened (or reopened) PR
+    pub fn open_pull(&self, repo: &str, pull: &PullInfo) {
+        let mut pr = Pull::new(repo, pull.number, &pull.title);
+        pr.set_head(&pull.head_sha);
+        pr.set_head_label(&pull.head_label);
+        pr.set_author(&pull.author);
+        pr.set_base(&pull.base);
+        self.record_audit(&mut pr, Source::Webhook);
+        let mut prs = self.prs.lock().unwrap();
+        prs.retain(|p| !(p.num == pull.number && p.repo == repo));
+        prs.push(pr);
+    }
+
+    /// Stop tracking a closed or merged PR
+    pub fn close_pull(&self, repo: &str, num: u64) {
+        info!("{}#{} closed - removing from queue", repo, num);
+        let mut prs = self.prs.lock().unwrap();
+        self.untrack(&mut prs,
+                     "closed",
+                     Source::Webhook,
+                     |pr| pr.num == num && pr.repo == repo);
+        drop(prs);
+        // closing a PR under test frees its queue
+        self.queue_for(repo);
+    }
+
+    /// Apply a change to a tracked PR and record what happened to it
+    ///
+    /// The change may make the PR testable (e.g. removing a block label), so the queue
+    /// of the repository runs after.
+    pub fn update_pull<F>(&self, repo: &str, num: u64, f: F)
+        where F: FnOnce(&mut Pull)
+    {
+        {
+            let mut prs = self.prs.lock().unwrap();
+            match prs.iter_mut().find(|pr| pr.num == num && pr.repo == repo) {
+                Some(pr) => {
+                    f(pr);
+                    self.record_audit(pr, Source::Webhook);
+                }
+                None => {
+                    warn!("ignoring event on untracked pr {}#{}", repo, num);
+                    return;
+                }
+            }
+        }
+        self.queue_for(repo);
+    }
+
+    /// Apply a label added to or removed from a PR if it has a special meaning
+    pub fn apply_label(&self, pr: &mut Pull, label: &str, added: bool) {
+        if let Some(cfg) = self.repository(&pr.repo) {
+            if cfg.labels.rollup.as_ref().map_or(false, |l| l == label) {
+                pr.set_rollup(added);
+            }
+            if cfg.labels.block.as_ref().map_or(false, |l| l == label) {
+                if added {
+                    if !pr.block() {
+                        info!("{}#{} - already testing, not blocked", pr.repo, pr.num);
+                    }
+                } else {
+                    pr.unblock();
+                }
+            }
+        }
+    }
+
+    /// Apply tree and queue commands in a comment on a PR
+    ///
+    /// Approvals, retries and reopened trees start the next build right away.
+    pub fn comment_pull(&self, repo: &str, num: u64, user: &str, body: &str, source: Source) {
+        let tree = parse_tree_command(body);
+        {
+            let mut prs = self.prs.lock().unwrap();
+            let pr = match prs.iter_mut().find(|ref pr| pr.num == num && pr.repo == repo) {
+                Some(pr) => pr,
+                None => {
+                    warn!("ignoring comment on untracked pr {}", num);
+                    return;
+                }
+            };
+            debug!("found corresponding pr {}", pr.num);
+            if let Some(closed) = tree {
+                if self.set_tree(&pr.repo, &pr.base, closed, user) {
+                    let cmd = closed.map_or("treeopen".into(), |p| format!("treeclosed={}", p));
+                    pr.record_command(user, &cmd);
+                }
+            }
+            let reviewers = self.repository(&pr.repo)
+                .map(|r| r.reviewers().to_vec())
+                .unwrap_or_default();
+            let n = parse_commands(pr, body.into(), user.into(), &reviewers);
+            self.metrics.commands(n);
+            self.record_audit(pr, source);
+        }
+        self.queue_for(repo);
+    }
+
+    /// Move PRs whose head branch was pushed, and recheck mergeability after base pushes
+    ///
+    /// Pushes changing `.volf.toml` on the branch it is read from reload it.
+    pub fn push_branch(&self, repo: &str, branch: &str, sha: &str, pusher: &str,
+                       changed_config: bool) {
+        let owner = repo.split('/').next().unwrap_or("");
+        let label = format!("{}:{}", owner, branch);
+
+        let cfg = self.repository(repo);
+        let queues = cfg.as_ref().map(|r| r.queues()).unwrap_or_default();
+        if queues.iter().any(|q| q.auto == branch) {
+            let forge = cfg.as_ref().map(|r| r.forge).unwrap_or_default();
+            if pusher != self.cfg().forge_login(forge) {
+                self.metrics.foreign_auto_push();
+                warn!("{} pushed {} to {} in {} - only volf should push there",
+                      pusher,
+                      sha,
+                      branch,
+                      repo);
+            }
+            return;
+        }
+        if let Some(ref r) = cfg {
+            if changed_config && r.repo_config() && r.config_branch() == branch {
+                info!("{} pushed to {} in {}", REPO_FILE, branch, repo);
+                let _ = self.load_repo_file(r)
+                    .map_err(|e| warn!("Failed to read {} of {}: {}", REPO_FILE, repo, e));
+            }
+        }
+
+        let mut on_base = false;
+        {
+            let mut prs = self.prs.lock().unwrap();
+            for pr in prs.iter_mut().filter(|pr| pr.repo == repo) {
+                if pr.head_label == label {
+                    info!("{}#{} pushed to {}", repo, pr.num, sha);
+                    pr.set_head(sha);
+                    self.record_audit(pr, Source::Webhook);
+                }
+                if pr.base == branch {
+                    on_base = true;
+                }
+            }
+        }
+        if on_base {
+            info!("{} pushed to {} - rechecking mergeability", repo, branch);
+            let delay = self.recheck_delay;
+            if delay == Duration::from_secs(0) {
+                self.check_mergeable(repo, branch);
+            } else {
+                let srv = self.clone();
+                let (repo, branch) = (repo.to_string(), branch.to_string());
+                thread::spawn(move || {
+                    thread::sleep(delay);
+                    srv.check_mergeable(&repo, &branch)
+                });
+            }
+        }
+    }
+
+    /// Refresh mergeability of every PR targeting a base branch
+    pub fn check_mergeable(&self, repo: &str, base: &str) {
+        let forge = match self.forge(repo) {
+            Ok(forge) => forge,
+            Err(e) => {
+                warn!("Failed to check mergeability in {}: {}", repo, e);
+                return;
+            }
+        };
+        let nums = {
+            let prs = self.prs.lock().unwrap();
+            prs.iter()
+                .filter(|pr| pr.repo == repo && pr.base == base)
+                .map(|pr| pr.num)
+                .collect::<Vec<_>>()
+        };
+        for num in nums {
+            match forge.mergeable(repo, num) {
+                Ok(Some(mergeable)) => {
+                    let mut prs = self.prs.lock().unwrap();
+                    if let Some(pr) = prs.iter_mut().find(|pr| pr.repo == repo && pr.num == num) {
+                        pr.set_mergeable(mergeable);
+                    }
+                }
+                Ok(None) => debug!("mergeability of {}#{} not computed yet", repo, num),
+                Err(e) => warn!("Failed to check mergeability of {}#{}: {}", repo, num, e),
+            }
+        }
+        // PRs that merge cleanly again can be tested
+        self.queue_for(repo);
+    }
+
+    /// Close the tree of a queue below a priority (None reopens it)
+    ///
+    /// Only reviewers of the repository may change the tree state.
+    /// Returns whether the command was allowed.
+    fn set_tree(&self, repo: &str, base: &str, closed: Option<u32>, user: &str) -> bool {
+        let allowed = self.repository(repo)
+            .map_or(false, |r| r.reviewers().iter().any(|u| u == user));
+        if !allowed {
+            warn!("ignoring tree command on {}:{} from {}", repo, base, user);
+            return false;
+        }
+        let mut trees = self.trees.lock().unwrap();
+        let key = (repo.to_string(), base.to_string());
+        match closed {
+            Some(p) => {
+                info!("{}:{} - treeclosed={} from {}", repo, base, p, user);
+                trees.insert(key, p);
+            }
+            None => {
+                info!("{}:{} - treeopen from {}", repo, base, user);
+                trees.remove(&key);
+            }
+        }
+        self.metrics.commands(1);
+        true
+    }
+}
+
+/// Github event handlers
 impl ServerHandle {
-    fn handle_push(&self, _: Push) -> VolfResult<()> {
-        // TODO: need `ref` key here to match up with a pr
+    fn handle_push(&self, data: Push) -> VolfResult<()> {
+        let repo = &data.repository.full_name;
+        if !data.git_ref.starts_with("refs/heads/") {
+            debug!("ignoring push to {} in {}", data.git_ref, repo);
+            return Ok(());
+        }
+        let branch = &data.git_ref["refs/heads/".len()..];
+        let changed_config = data.commits.iter().any(|c| c.touches(REPO_FILE));
+        self.push_branch(repo, branch, &data.after, &data.sender.login, changed_config);
         Ok(())
     }
 
     fn handle_pull_request(&self, data: PullRequest) -> VolfResult<()> {
         info!("got pr {:?}", data);
         let prdata = &data.pull_request;
-        if data.action == "opened" || data.action == "reopened" {
-            let pr = Pull::new(&data.repository.full_name, data.number, &prdata.title);
-            let mut prs = self.prs.lock().unwrap();
-            prs.push(pr);
+        let repo = &data.repository.full_name;
+        if prdata.head.git_ref.starts_with(MERGE_PREFIX) {
+            debug!("ignoring temporary PR {}#{}", repo, data.number);
+            return Ok(());
+        }
+
+        match data.action.as_ref() {
+            "opened" | "reopened" => {
+                let pull = PullInfo {
+                    number: data.number,
+                    title: prdata.title.clone(),
+                    head_sha: prdata.head.sha.clone(),
+                    head_label: prdata.head.label.clone(),
+                    base: prdata.base.git_ref.clone(),
+                    author: prdata.user.login.clone(),
+                };
+                self.open_pull(repo, &pull);
+            }
+            "closed" => self.close_pull(repo, data.number),
+            "edited" => {
+                let retargeted = data.changes.as_ref().map_or(false, |c| c.base.is_some());
+                self.update_pull(repo, data.number, |pr| {
+                    pr.set_title(&prdata.title);
+                    if retargeted {
+                        info!("{}#{} retargeted to {}", repo, data.number, prdata.base.git_ref);
+                        pr.set_base(&prdata.base.git_ref);
+                    }
+                })
+            }
+            "synchronize" | "synchronized" => {
+                info!("{}#{} pushed to {}", repo, data.number, prdata.head.sha);
+                self.update_pull(repo, data.number, |pr| pr.set_head(&prdata.head.sha))
+            }
+            "labeled" | "unlabeled" => {
+                if let Some(ref label) = data.label {
+                    let added = data.action == "labeled";
+                    self.update_pull(repo,
+                                     data.number,
+                                     |pr| self.apply_label(pr, &label.name, added))
+                }
+            }
+            // gitea sends the full label set instead of the changed label
+            "label_updated" | "label_cleared" => {
+                let labels = self.repository(repo).map(|r| r.labels);
+                if let Some(labels) = labels {
+                    let configured =
+                        labels.rollup.into_iter().chain(labels.block).collect::<Vec<_>>();
+                    self.update_pull(repo, data.number, |pr| {
+                        for l in &configured {
+                            let set = prdata.labels.iter().any(|pl| &pl.name == l);
+                            self.apply_label(pr, l, set);
+                        }
+                    })
+                }
+            }
+            _ => {}
         }
         Ok(())
     }
 
     fn handle_issue_comment(&self, data: IssueComment) -> VolfResult<()> {
         info!("got issue comment {:?}", data);
-        if let Some(ref prdata) = data.issue.pull_request {
+        if data.issue.pull_request.is_some() || data.is_pull {
             if data.action == "created" {
                 debug!("Comment on {}#{} by {} - {}",
                        data.repository.full_name,
@@ -155,17 +566,91 @@ impl ServerHandle {
                        data.comment.body,
                 );
             }
-            let mut prs = self.prs.lock().unwrap();
+            self.comment_pull(&data.repository.full_name,
+                              data.issue.number,
+                              &data.sender.login,
+                              &data.comment.body,
+                              Source::Comment);
+        }
+        Ok(())
+    }
 
-            if let Some(pr) = prs.iter_mut()
-                   .find(|ref pr| {
-                pr.num == prdata.number && pr.repo == data.repository.full_name
-            }) {
-                debug!("found corresponding pr {}", pr.num);
-                parse_commands(pr, data.comment.body, data.sender.login);
-            } else {
-                warn!("ignoring comment on untracked pr {}", prdata.number);
+    fn handle_pull_request_review(&self, data: PullRequestReview) -> VolfResult<()> {
+        info!("got review {:?}", data);
+        if data.action == "submitted" {
+            if let Some(ref body) = data.review.body {
+                self.comment_pull(&data.repository.full_name,
+                                  data.pull_request.number,
+                                  &data.review.user.login,
+                                  body,
+                                  Source::Review);
+            }
+        }
+        Ok(())
+    }
+
+    /// Start tracking repositories the app was installed on
+    fn install_repositories(&self, id: u64, repos: &[Repository]) -> VolfResult<()> {
+        let app = self.app.as_ref().ok_or_else(|| VolfError::SpammyGithub("installation".into()))?;
+        let names = repos.iter().map(|r| r.full_name.clone()).collect::<Vec<_>>();
+        info!("Installation {} added {:?}", id, names);
+        app.install(id, &names);
+        self.refresh_repositories();
+        // pick up PRs opened before the installation without blocking the webhook
+        let srv = self.clone();
+        thread::spawn(move || {
+            for repo in names.iter().filter_map(|n| srv.repository(n)) {
+                let _ = srv.synchronize(&repo)
+                    .map_err(|e| error!("Failed to synchronize {}: {}", repo.name, e));
+            }
+        });
+        Ok(())
+    }
+
+    /// Stop tracking repositories the app was removed from
+    ///
+    /// Repositories the config still tracks keep their PRs. PRs under test in the others
+    /// are kept until their build finishes.
+    fn uninstall_repositories(&self, repos: &[String]) {
+        info!("Uninstalled from {:?}", repos);
+        self.refresh_repositories();
+        let gone = repos.iter().filter(|r| self.repository(r).is_none()).collect::<Vec<_>>();
+        let mut prs = self.prs.lock().unwrap();
+        self.untrack(&mut prs, "app uninstalled", Source::Webhook, |pr| {
+            pr.state != Progress::Testing && gone.contains(&&pr.repo)
+        });
+    }
+
+    fn handle_installation(&self, data: Installation) -> VolfResult<()> {
+        let id = data.installation.id;
+        match data.action.as_ref() {
+            "created" => self.install_repositories(id, &data.repositories)?,
+            "deleted" => {
+                if let Some(ref app) = self.app {
+                    self.uninstall_repositories(&app.remove_installation(id));
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn handle_installation_repositories(&self, data: InstallationRepositories)
+                                        -> VolfResult<()> {
+        let id = data.installation.id;
+        match data.action.as_ref() {
+            "added" => self.install_repositories(id, &data.repositories_added)?,
+            "removed" => {
+                if let Some(ref app) = self.app {
+                    let names = data.repositories_removed
+                        .iter()
+                        .map(|r| r.full_name.clone())
+                        .collect::<Vec<_>>();
+                    app.uninstall(&names);
+                    self.uninstall_repositories(&names);
+                }
             }
+            _ => {}
         }
         Ok(())
     }
@@ -177,11 +662,21 @@ impl ServerHandle {
 
     /// Event multiplexer
     pub fn handle_event(&self, event: &str, payload: &str) -> VolfResult<()> {
+        self.metrics.event(event);
         match event {
-            "issue_comment" => self.handle_issue_comment(serde_json::from_str(&payload)?),
+            "issue_comment" | "pull_request_comment" => {
+                self.handle_issue_comment(serde_json::from_str(&payload)?)
+            }
             "pull_request" => self.handle_pull_request(serde_json::from_str(&payload)?),
+            "pull_request_review" => {
+                self.handle_pull_request_review(serde_json::from_str(&payload)?)
+            }
             "push" => self.handle_push(serde_json::from_str(&payload)?),
             "ping" => self.handle_ping(serde_json::from_str(&payload)?),
+            "installation" => self.handle_installation(serde_json::from_str(&payload)?),
+            "installation_repositories" => {
+                self.handle_installation_repositories(serde_json::from_str(&payload)?)
+            }
             _ => Err(VolfError::SpammyGithub(event.into())),
         }
     }
@@ -201,10 +696,47 @@ header! {(XGithubEvent, "X-Github-Event") => [String]}
 /// unique id for each delivery
 header! {(XGithubDelivery, "X-Github-Delivery") => [String]}
 
+/// Repository every repository event refers to
+#[derive(Deserialize, Debug)]
+struct RepositoryEvent {
+    repository: Repository,
+}
 
 /// A Handler equivalent implementation for our state struct
 impl ServerHandle {
-    pub fn handle_webhook(&self, mut req: Request, res: Response) {
+    /// Check a github payload against the secret of the repository it refers to
+    ///
+    /// Installation events are only signed with the webhook secret of the app, which
+    /// also signs events of the repositories it is installed on.
+    fn verify_github(&self, event: &str, payload: &str, signature: &str) -> VolfResult<()> {
+        let cfg = self.cfg();
+        let app_signed = match cfg.github.app {
+            Some(ref app) => {
+                self.app.is_some() && !app.webhook_secret.is_empty() &&
+                auth::verify(&app.webhook_secret, payload, signature)
+            }
+            None => false,
+        };
+        if event == "installation" || event == "installation_repositories" {
+            return if app_signed {
+                Ok(())
+            } else {
+                Err(VolfError::InvalidSignature(event.into()))
+            };
+        }
+        let data: RepositoryEvent = serde_json::from_str(payload)?;
+        let name = data.repository.full_name;
+        let installed = self.app.as_ref().map(|a| a.installed(&name)).unwrap_or(false);
+        match self.repository(&name) {
+            Some(ref r) if r.forge == ForgeKind::Github &&
+                       (installed && app_signed ||
+                        !r.github_secret().is_empty() &&
+                        auth::verify(r.github_secret(), payload, signature)) => Ok(()),
+            _ => Err(VolfError::InvalidSignature(name)),
+        }
+    }
+
+    pub fn handle_webhook(&self, mut req: Request, mut res: Response) {
         let mut payload = String::new();
         let headers = req.headers.clone();
         if let (Some(&XGithubEvent(ref event)),
@@ -215,12 +747,79 @@ impl ServerHandle {
              headers.get::<XHubSignature>()) {
             if let Ok(_) = req.read_to_string(&mut payload) {
                 debug!("github event: {}", event);
-                // TODO: verify signature sha1 value == sha1(github.secret)
-                trace!("signature: {}", signature);
+                if let Err(e) = self.verify_github(event, &payload, signature) {
+                    warn!("Rejecting github {}: {}", event, e);
+                    *res.status_mut() = StatusCode::Unauthorized;
+                    return;
+                }
                 trace!("id {}", id);
-                let _ = self.handle_event(&event, &payload)
-                    .map_err(|err| warn!("Failed to handle {} : {}", event, err));
+                if self.record_delivery(ForgeKind::Github, id, event, &payload) {
+                    let _ = self.handle_event(&event, &payload)
+                        .map_err(|err| warn!("Failed to handle {} : {}", event, err));
+                } else {
+                    info!("Ignoring redelivered {} {}", event, id);
+                }
+            }
+        } else {
+            warn!("Rejecting github event without signature");
+            *res.status_mut() = StatusCode::Unauthorized;
+            return;
+        }
+        res.send(b"ok").ok();
+    }
+}
+
+// -----------------------------------------------------------------------------
+// gitea webhook handler
+
+/// name of Gitea event (mostly the github names)
+header! {(XGiteaEvent, "X-Gitea-Event") => [String]}
+
+/// hex HMAC-SHA256 of the payload with the repository secret
+header! {(XGiteaSignature, "X-Gitea-Signature") => [String]}
+
+/// unique id for each delivery
+header! {(XGiteaDelivery, "X-Gitea-Delivery") => [String]}
+
+impl ServerHandle {
+    /// Check a gitea payload against the secret of the repository it refers to
+    fn verify_gitea(&self, payload: &str, signature: &str) -> VolfResult<()> {
+        let data: RepositoryEvent = serde_json::from_str(payload)?;
+        let name = data.repository.full_name;
+        match self.repository(&name) {
+            Some(ref r) if r.forge == ForgeKind::Gitea &&
+                       auth::verify_sha256(r.github_secret(), payload, signature) => Ok(()),
+            _ => Err(VolfError::InvalidSignature(name)),
+        }
+    }
+
+    pub fn handle_gitea_webhook(&self, mut req: Request, mut res: Response) {
+        let mut payload = String::new();
+        let headers = req.headers.clone();
+        if let (Some(&XGiteaEvent(ref event)),
+                Some(&XGiteaDelivery(ref id)),
+                Some(&XGiteaSignature(ref signature))) =
+            (headers.get::<XGiteaEvent>(),
+             headers.get::<XGiteaDelivery>(),
+             headers.get::<XGiteaSignature>()) {
+            if let Ok(_) = req.read_to_string(&mut payload) {
+                debug!("gitea event: {}", event);
+                if let Err(e) = self.verify_gitea(&payload, signature) {
+                    warn!("Rejecting gitea {}: {}", event, e);
+                    *res.status_mut() = StatusCode::Unauthorized;
+                    return;
+                }
+                if self.record_delivery(ForgeKind::Gitea, id, event, &payload) {
+                    let _ = self.handle_event(&event, &payload)
+                        .map_err(|err| warn!("Failed to handle {} : {}", event, err));
+                } else {
+                    info!("Ignoring redelivered {} {}", event, id);
+                }
             }
+        } else {
+            warn!("Rejecting gitea event without signature");
+            *res.status_mut() = StatusCode::Unauthorized;
+            return;
         }
         res.send(b"ok").ok();
     }